@@ -1,7 +1,65 @@
 #![deny(warnings, clippy::all)]
 
+use std::{env, fs, path::Path};
+
 fn main() {
     built::write_built_file().expect("Failed to acquire build-time information");
 
     env_config::generate_env_config_constants();
+
+    generate_heater_profile();
+
+    if env::var_os("CARGO_FEATURE_DEFMT").is_some() {
+        println!("cargo:rustc-link-arg=-Tdefmt.x");
+    }
+}
+
+/// Writes the `config::BME680_HEATER_PROFILE` expression from the environment:
+/// - BME680_HEATER_TEMPERATURE: target in degrees C, or `off` to disable the gas measurement (320)
+/// - BME680_HEATER_DURATION_MS: heating duration (150)
+/// - BME680_HEATER_AMBIENT_TEMPERATURE: fixed ambient temperature in degrees C, or `measured`
+fn generate_heater_profile() {
+    let var = |name: &str, default: &str| {
+        println!("cargo:rerun-if-env-changed={name}");
+        env::var(name).unwrap_or_else(|_| default.to_string())
+    };
+    let temperature = var("BME680_HEATER_TEMPERATURE", "320");
+    let duration_ms = var("BME680_HEATER_DURATION_MS", "150");
+    let ambient = var("BME680_HEATER_AMBIENT_TEMPERATURE", "measured");
+
+    let profile = if temperature.trim() == "off" {
+        "None".to_string()
+    } else {
+        let temperature: u16 = temperature
+            .trim()
+            .parse()
+            .ok()
+            .filter(|t| (1..=400).contains(t))
+            .expect("BME680_HEATER_TEMPERATURE must be 1..=400 or off");
+        let duration_ms: u16 = duration_ms
+            .trim()
+            .parse()
+            .ok()
+            .filter(|d| (1..=4032).contains(d))
+            .expect("BME680_HEATER_DURATION_MS must be 1..=4032");
+        let ambient = if ambient.trim() == "measured" {
+            "crate::sensors::bme680::AmbientTemperatureSource::Measured".to_string()
+        } else {
+            let t: i8 = ambient
+                .trim()
+                .parse()
+                .expect("BME680_HEATER_AMBIENT_TEMPERATURE must be degrees C or measured");
+            format!("crate::sensors::bme680::AmbientTemperatureSource::Fixed({t})")
+        };
+        format!(
+            "Some(crate::sensors::bme680::HeaterProfile {{ target_temperature: {temperature}, duration_ms: {duration_ms}, ambient_temperature: {ambient} }})"
+        )
+    };
+
+    let out_dir = env::var("OUT_DIR").expect("OUT_DIR is set by cargo");
+    fs::write(
+        Path::new(&out_dir).join("bme680_heater_profile.rs"),
+        profile,
+    )
+    .expect("Failed to write the heater profile");
 }
@@ -0,0 +1,6 @@
+#![deny(warnings, clippy::all)]
+
+fn main() {
+    // Same environment-provided constants as the firmware (ports, addresses, etc)
+    env_config::generate_env_config_constants();
+}
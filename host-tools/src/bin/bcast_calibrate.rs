@@ -0,0 +1,209 @@
+#![deny(warnings, clippy::all)]
+
+use clap::Parser;
+use host_tools::{
+    calibration::{align, Fit, Sample},
+    config,
+    message::{self, Received},
+    record,
+};
+use std::{
+    collections::BTreeMap,
+    net::{Ipv4Addr, SocketAddr, UdpSocket},
+    path::PathBuf,
+    time::{Duration, Instant},
+};
+
+/// Fit the temperature and humidity of other devices against this reference device
+#[derive(Parser, Debug, Clone)]
+#[clap(version)]
+struct Opts {
+    /// Device ID of the reference device
+    #[clap(short = 'r', long, default_value_t = config::DEVICE_ID)]
+    reference: u16,
+
+    /// Maximum time between a device's reading and the reference's readings
+    #[clap(long, default_value_t = 10_000)]
+    max_skew_ms: u64,
+
+    /// Listen for this many seconds instead of reading recordings
+    #[clap(short = 'l', long, conflicts_with = "files")]
+    listen_secs: Option<u64>,
+
+    /// UDP port to listen on
+    #[clap(short = 'p', long, default_value_t = config::BROADCAST_PORT)]
+    port: u16,
+
+    /// Print an env-config snippet with the suggested offsets for each device
+    #[clap(short = 'e', long)]
+    env_config: bool,
+
+    /// Name of the temperature offset variable in the env-config snippet
+    #[clap(long, default_value = "TEMPERATURE_OFFSET")]
+    temperature_offset_var: String,
+
+    /// Name of the humidity offset variable in the env-config snippet
+    #[clap(long, default_value = "HUMIDITY_OFFSET")]
+    humidity_offset_var: String,
+
+    /// Recordings from bcast-recorder
+    #[clap(required_unless_present = "listen_secs")]
+    files: Vec<PathBuf>,
+}
+
+/// Valid temperature and humidity readings of a device
+#[derive(Default)]
+struct Readings {
+    temperature: Vec<Sample>,
+    humidity: Vec<Sample>,
+}
+
+impl Readings {
+    fn push(&mut self, rx: &Received) {
+        let m = &rx.msg;
+        if m.status_flags.temperature_valid() {
+            self.temperature.push(Sample {
+                timestamp_ms: rx.timestamp_ms,
+                value: message::temperature_c(m),
+            });
+        }
+        if m.status_flags.humidity_valid() {
+            self.humidity.push(Sample {
+                timestamp_ms: rx.timestamp_ms,
+                value: message::humidity_percent(m),
+            });
+        }
+    }
+}
+
+fn main() -> anyhow::Result<()> {
+    let opts = Opts::parse();
+
+    let received = match opts.listen_secs {
+        Some(secs) => listen(opts.port, Duration::from_secs(secs))?,
+        None => read_recordings(&opts.files)?,
+    };
+
+    let mut devices: BTreeMap<u16, Readings> = BTreeMap::new();
+    for rx in received.iter() {
+        devices.entry(rx.msg.device_id).or_default().push(rx);
+    }
+
+    let reference = devices.remove(&opts.reference).ok_or_else(|| {
+        anyhow::anyhow!("No messages from the reference device {}", opts.reference)
+    })?;
+    println!(
+        "Reference device {}: {} temperature, {} humidity readings",
+        opts.reference,
+        reference.temperature.len(),
+        reference.humidity.len()
+    );
+
+    for (device_id, readings) in devices.iter() {
+        println!();
+        println!("Device {device_id}");
+        let temp = report(
+            "temperature (C)",
+            &reference.temperature,
+            &readings.temperature,
+            opts.max_skew_ms,
+        );
+        let hum = report(
+            "humidity (%)",
+            &reference.humidity,
+            &readings.humidity,
+            opts.max_skew_ms,
+        );
+
+        if opts.env_config {
+            // Offsets are in the wire format units, centi-degrees and centi-percent
+            println!("  # env-config, device {device_id}");
+            if let Some(fit) = temp {
+                println!(
+                    "  {}={}",
+                    opts.temperature_offset_var,
+                    (fit.mean_offset * 100.0).round() as i32
+                );
+            }
+            if let Some(fit) = hum {
+                println!(
+                    "  {}={}",
+                    opts.humidity_offset_var,
+                    (fit.mean_offset * 100.0).round() as i32
+                );
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn report(name: &str, reference: &[Sample], device: &[Sample], max_skew_ms: u64) -> Option<Fit> {
+    let pairs = align(reference, device, max_skew_ms);
+    let fit = Fit::linear(&pairs);
+    match &fit {
+        None => println!("  {name}: not enough aligned readings ({})", pairs.len()),
+        Some(f) => {
+            // ~95% confidence intervals
+            println!("  {name}: {} aligned readings", f.samples);
+            println!(
+                "    reference = {:.4} (+/- {:.4}) * device + {:.3} (+/- {:.3})",
+                f.gain,
+                1.96 * f.gain_stderr,
+                f.offset,
+                1.96 * f.offset_stderr
+            );
+            println!(
+                "    residual stddev {:.3}, r^2 {:.4}, mean offset {:.3}",
+                f.residual_stddev, f.r_squared, f.mean_offset
+            );
+        }
+    }
+    fit
+}
+
+fn listen(port: u16, duration: Duration) -> anyhow::Result<Vec<Received>> {
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, port))?;
+    eprintln!("Listening on {} for {duration:?}", socket.local_addr()?);
+
+    let start = Instant::now();
+    let mut received = Vec::new();
+    let mut buf = [0_u8; 1500];
+    while let Some(remaining) = duration.checked_sub(start.elapsed()) {
+        if remaining.is_zero() {
+            break;
+        }
+        socket.set_read_timeout(Some(remaining))?;
+        let (len, source) = match socket.recv_from(&mut buf) {
+            Ok(r) => r,
+            Err(e)
+                if matches!(
+                    e.kind(),
+                    std::io::ErrorKind::WouldBlock | std::io::ErrorKind::TimedOut
+                ) =>
+            {
+                break
+            }
+            Err(e) => return Err(e.into()),
+        };
+        match Received::decode(&buf[..len], source) {
+            Ok(rx) => received.push(rx),
+            Err(e) => eprintln!("{e}"),
+        }
+    }
+    Ok(received)
+}
+
+fn read_recordings(files: &[PathBuf]) -> anyhow::Result<Vec<Received>> {
+    // The source address isn't needed for the fits
+    let source = SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0));
+    let mut received = Vec::new();
+    for f in files.iter() {
+        for frame in record::read_frames(f)? {
+            let mut rx = Received::decode(&frame.frame, source)?;
+            rx.timestamp_ms = frame.timestamp_ms;
+            received.push(rx);
+        }
+    }
+    Ok(received)
+}
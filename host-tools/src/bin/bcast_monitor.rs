@@ -0,0 +1,98 @@
+#![deny(warnings, clippy::all)]
+
+use clap::Parser;
+use host_tools::{
+    config,
+    message::{self, Received},
+    tracker::Tracker,
+};
+use std::{
+    io::{self, Write},
+    net::{Ipv4Addr, UdpSocket},
+};
+
+/// Receive and decode broadcast protocol messages
+#[derive(Parser, Debug, Clone)]
+#[clap(version)]
+struct Opts {
+    /// UDP port to listen on
+    #[clap(short = 'p', long, default_value_t = config::BROADCAST_PORT)]
+    port: u16,
+
+    /// Print each message as a line of JSON instead of the live table
+    #[clap(short = 'j', long)]
+    json: bool,
+}
+
+fn main() -> anyhow::Result<()> {
+    let opts = Opts::parse();
+
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, opts.port))?;
+    eprintln!("Listening on {}", socket.local_addr()?);
+
+    let mut tracker = Tracker::new();
+    let mut buf = [0_u8; 1500];
+    let stdout = io::stdout();
+    loop {
+        let (len, source) = socket.recv_from(&mut buf)?;
+        let rx = match Received::decode(&buf[..len], source) {
+            Ok(rx) => rx,
+            Err(e) => {
+                eprintln!("{e}");
+                continue;
+            }
+        };
+
+        let mut out = stdout.lock();
+        if opts.json {
+            let mut line = rx.to_json();
+            let event = tracker.update(rx);
+            line["event"] = format!("{event:?}").into();
+            writeln!(out, "{line}")?;
+        } else {
+            tracker.update(rx);
+            print_table(&mut out, &tracker)?;
+        }
+        out.flush()?;
+    }
+}
+
+fn print_table<W: Write>(w: &mut W, tracker: &Tracker) -> io::Result<()> {
+    // Clear the screen and move the cursor home
+    write!(w, "\x1b[2J\x1b[H")?;
+    writeln!(
+        w,
+        "{:<26} {:<18} {:>5} {:>10} {:>9} {:>8} {:>6} {:>8} {:>6} {:>5} {:>7}  flags",
+        "serial",
+        "source",
+        "id",
+        "seqnum",
+        "uptime",
+        "temp C",
+        "hum %",
+        "voc",
+        "rx",
+        "miss",
+        "resets"
+    )?;
+    for (key, dev) in tracker.devices() {
+        let m = &dev.last.msg;
+        writeln!(
+            w,
+            "{:<26} {:<18} {:>5} {:>10} {:>9} {:>8.2} {:>6.2} {:>8} {:>6} {:>5} {:>7}  {}",
+            key,
+            dev.last.source.ip().to_string(),
+            m.device_id,
+            m.sequence_number,
+            m.uptime_seconds,
+            message::temperature_c(m),
+            message::humidity_percent(m),
+            m.voc_index,
+            dev.received,
+            dev.missed,
+            dev.uptime_resets,
+            message::status_flags_str(&m.status_flags),
+        )?;
+    }
+    Ok(())
+}
@@ -0,0 +1,97 @@
+#![deny(warnings, clippy::all)]
+
+use clap::{Parser, Subcommand};
+use host_tools::{config, message::Received, record};
+use std::{
+    net::{Ipv4Addr, SocketAddr, UdpSocket},
+    path::PathBuf,
+    thread,
+};
+
+/// Record broadcast protocol messages to CSV files, or replay recordings
+#[derive(Parser, Debug, Clone)]
+#[clap(version)]
+struct Opts {
+    #[clap(subcommand)]
+    cmd: Command,
+}
+
+#[derive(Subcommand, Debug, Clone)]
+enum Command {
+    /// Record the messages of every device, one set of files per device
+    Record {
+        /// UDP port to listen on
+        #[clap(short = 'p', long, default_value_t = config::BROADCAST_PORT)]
+        port: u16,
+
+        /// Number of rows per file before starting a new one
+        #[clap(short = 'r', long, default_value_t = 100_000)]
+        max_rows: usize,
+
+        /// Output directory
+        #[clap(default_value = "recordings")]
+        dir: PathBuf,
+    },
+
+    /// Re-send the recorded frames as UDP, with their original timing
+    Replay {
+        /// Destination address
+        #[clap(short = 'd', long, default_value_t = SocketAddr::from((Ipv4Addr::LOCALHOST, config::BROADCAST_PORT)))]
+        dest: SocketAddr,
+
+        /// Playback speed multiplier
+        #[clap(short = 's', long, default_value_t = 1.0)]
+        speed: f64,
+
+        /// Recording files, frames from several files are interleaved by timestamp
+        #[clap(required = true)]
+        files: Vec<PathBuf>,
+    },
+}
+
+fn main() -> anyhow::Result<()> {
+    let opts = Opts::parse();
+    match opts.cmd {
+        Command::Record {
+            port,
+            max_rows,
+            dir,
+        } => run_record(port, max_rows, dir),
+        Command::Replay { dest, speed, files } => run_replay(dest, speed, files),
+    }
+}
+
+fn run_record(port: u16, max_rows: usize, dir: PathBuf) -> anyhow::Result<()> {
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, port))?;
+    eprintln!("Listening on {}", socket.local_addr()?);
+
+    let mut recorder = record::Recorder::new(dir, max_rows)?;
+    let mut buf = [0_u8; 1500];
+    loop {
+        let (len, source) = socket.recv_from(&mut buf)?;
+        match Received::decode(&buf[..len], source) {
+            Ok(rx) => recorder.record(&rx)?,
+            Err(e) => eprintln!("{e}"),
+        }
+    }
+}
+
+fn run_replay(dest: SocketAddr, speed: f64, files: Vec<PathBuf>) -> anyhow::Result<()> {
+    anyhow::ensure!(speed > 0.0, "The speed must be positive");
+
+    let recordings = files
+        .iter()
+        .map(record::read_frames)
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    let frames = record::merge(recordings);
+
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))?;
+    socket.set_broadcast(true)?;
+    eprintln!("Replaying {} frames to {dest}", frames.len());
+
+    for (f, delay) in frames.iter().zip(record::replay_delays(&frames, speed)) {
+        thread::sleep(delay);
+        socket.send_to(&f.frame, dest)?;
+    }
+    Ok(())
+}
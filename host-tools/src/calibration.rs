@@ -0,0 +1,205 @@
+//! Time alignment and linear fits of a device's readings against the reference.
+
+/// A reading at a host receive time
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct Sample {
+    pub timestamp_ms: u64,
+    pub value: f64,
+}
+
+/// Pair each device sample with the reference value at the same time.
+///
+/// The reference is linearly interpolated between its two neighbouring samples,
+/// device samples without a reference sample within `max_skew_ms` are dropped.
+/// Returns `(device, reference)` pairs.
+pub fn align(reference: &[Sample], device: &[Sample], max_skew_ms: u64) -> Vec<(f64, f64)> {
+    let mut reference = reference.to_vec();
+    reference.sort_by_key(|s| s.timestamp_ms);
+
+    device
+        .iter()
+        .filter_map(|d| {
+            let t = d.timestamp_ms;
+            let idx = reference.partition_point(|r| r.timestamp_ms < t);
+            let after = reference.get(idx);
+            let before = idx.checked_sub(1).and_then(|i| reference.get(i));
+            let within = |r: &Sample| r.timestamp_ms.abs_diff(t) <= max_skew_ms;
+            let ref_value = match (before, after) {
+                (Some(b), Some(a)) if within(b) && within(a) => {
+                    if a.timestamp_ms == b.timestamp_ms {
+                        a.value
+                    } else {
+                        let frac =
+                            (t - b.timestamp_ms) as f64 / (a.timestamp_ms - b.timestamp_ms) as f64;
+                        b.value + frac * (a.value - b.value)
+                    }
+                }
+                (Some(b), _) if within(b) => b.value,
+                (_, Some(a)) if within(a) => a.value,
+                _ => return None,
+            };
+            Some((d.value, ref_value))
+        })
+        .collect()
+}
+
+/// Least squares fit of `reference = gain * device + offset`
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct Fit {
+    pub samples: usize,
+    pub gain: f64,
+    pub offset: f64,
+    /// Standard error of the gain
+    pub gain_stderr: f64,
+    /// Standard error of the offset
+    pub offset_stderr: f64,
+    /// Standard deviation of the residuals
+    pub residual_stddev: f64,
+    /// Coefficient of determination
+    pub r_squared: f64,
+    /// Mean of `reference - device`, the offset assuming a gain of 1
+    pub mean_offset: f64,
+}
+
+impl Fit {
+    /// Returns `None` with fewer than 3 pairs, or when the device readings don't vary
+    pub fn linear(pairs: &[(f64, f64)]) -> Option<Self> {
+        let n = pairs.len();
+        if n < 3 {
+            return None;
+        }
+        let nf = n as f64;
+        let mean_x = pairs.iter().map(|(x, _)| x).sum::<f64>() / nf;
+        let mean_y = pairs.iter().map(|(_, y)| y).sum::<f64>() / nf;
+        let sxx: f64 = pairs.iter().map(|(x, _)| (x - mean_x).powi(2)).sum();
+        let syy: f64 = pairs.iter().map(|(_, y)| (y - mean_y).powi(2)).sum();
+        let sxy: f64 = pairs.iter().map(|(x, y)| (x - mean_x) * (y - mean_y)).sum();
+        if sxx <= f64::EPSILON {
+            return None;
+        }
+
+        let gain = sxy / sxx;
+        let offset = mean_y - gain * mean_x;
+        let ssr: f64 = pairs
+            .iter()
+            .map(|(x, y)| (y - (gain * x + offset)).powi(2))
+            .sum();
+        let residual_var = ssr / (nf - 2.0);
+        let gain_stderr = (residual_var / sxx).sqrt();
+        let offset_stderr = (residual_var * (1.0 / nf + mean_x.powi(2) / sxx)).sqrt();
+        let r_squared = if syy <= f64::EPSILON {
+            1.0
+        } else {
+            1.0 - ssr / syy
+        };
+
+        Some(Self {
+            samples: n,
+            gain,
+            offset,
+            gain_stderr,
+            offset_stderr,
+            residual_stddev: residual_var.sqrt(),
+            r_squared,
+            mean_offset: mean_y - mean_x,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample(timestamp_ms: u64, value: f64) -> Sample {
+        Sample {
+            timestamp_ms,
+            value,
+        }
+    }
+
+    fn assert_close(a: f64, b: f64) {
+        assert!((a - b).abs() < 1e-9, "{a} != {b}");
+    }
+
+    #[test]
+    fn align_interpolates_the_reference() {
+        let reference = [
+            sample(1_000, 20.0),
+            sample(3_000, 22.0),
+            sample(5_000, 21.0),
+        ];
+        let device = [
+            sample(1_000, 19.0),
+            sample(1_500, 19.5),
+            sample(4_000, 20.5),
+        ];
+        let pairs = align(&reference, &device, 2_000);
+        assert_eq!(pairs, [(19.0, 20.0), (19.5, 20.5), (20.5, 21.5)]);
+    }
+
+    #[test]
+    fn align_sorts_the_reference() {
+        let reference = [sample(3_000, 22.0), sample(1_000, 20.0)];
+        let pairs = align(&reference, &[sample(2_000, 1.0)], 1_000);
+        assert_eq!(pairs, [(1.0, 21.0)]);
+    }
+
+    #[test]
+    fn align_drops_samples_without_a_close_reference() {
+        let reference = [sample(10_000, 20.0), sample(20_000, 30.0)];
+        let device = [
+            // Before and after the reference, within the skew of one end
+            sample(9_500, 1.0),
+            sample(20_800, 2.0),
+            // Too far from both ends
+            sample(15_000, 3.0),
+            sample(30_000, 4.0),
+            // Close to one neighbour only, no interpolation
+            sample(10_500, 5.0),
+        ];
+        let pairs = align(&reference, &device, 1_000);
+        assert_eq!(pairs, [(1.0, 20.0), (2.0, 30.0), (5.0, 20.0)]);
+        assert!(align(&[], &device, 1_000).is_empty());
+    }
+
+    #[test]
+    fn linear_fit_of_a_known_line() {
+        let pairs: Vec<_> = (0..10)
+            .map(|i| {
+                let x = 15.0 + f64::from(i);
+                (x, 1.02 * x - 0.75)
+            })
+            .collect();
+        let fit = Fit::linear(&pairs).unwrap();
+        assert_eq!(fit.samples, 10);
+        assert_close(fit.gain, 1.02);
+        assert_close(fit.offset, -0.75);
+        assert_close(fit.gain_stderr, 0.0);
+        assert_close(fit.residual_stddev, 0.0);
+        assert_close(fit.r_squared, 1.0);
+        // Mean x is 19.5
+        assert_close(fit.mean_offset, 0.02 * 19.5 - 0.75);
+    }
+
+    #[test]
+    fn linear_fit_with_residuals() {
+        // y = 2x + 1 with residuals of +1, -1, -1, +1 that don't change the fit
+        let pairs = [(0.0, 2.0), (1.0, 2.0), (2.0, 4.0), (3.0, 8.0)];
+        let fit = Fit::linear(&pairs).unwrap();
+        assert_close(fit.gain, 2.0);
+        assert_close(fit.offset, 1.0);
+        // 4 squared residuals of 1 over 2 degrees of freedom
+        assert_close(fit.residual_stddev, 2.0_f64.sqrt());
+        // Sxx is 5
+        assert_close(fit.gain_stderr, (2.0_f64 / 5.0).sqrt());
+        // Syy is 24, the residual sum of squares 4
+        assert_close(fit.r_squared, 1.0 - 4.0 / 24.0);
+    }
+
+    #[test]
+    fn linear_fit_needs_varying_device_readings() {
+        assert_eq!(Fit::linear(&[]), None);
+        assert_eq!(Fit::linear(&[(1.0, 2.0), (2.0, 3.0)]), None);
+        assert_eq!(Fit::linear(&[(1.0, 2.0), (1.0, 3.0), (1.0, 4.0)]), None);
+    }
+}
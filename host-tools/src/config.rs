@@ -0,0 +1,7 @@
+//! The environment-provided constants shared with the firmware
+
+pub use self::generated_confg::*;
+mod generated_confg {
+    #![allow(dead_code)]
+    include!(concat!(env!("OUT_DIR"), "/env_config.rs"));
+}
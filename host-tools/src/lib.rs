@@ -0,0 +1,7 @@
+#![deny(warnings, clippy::all)]
+
+pub mod calibration;
+pub mod config;
+pub mod message;
+pub mod record;
+pub mod tracker;
@@ -0,0 +1,158 @@
+use serde_json::{json, Value};
+use std::{net::SocketAddr, time::SystemTime};
+use wire_protocols::{
+    broadcast::{Message as WireMessage, Repr as Message},
+    StatusFlags,
+};
+
+/// A broadcast protocol message and where/when it was received
+#[derive(Clone, Debug)]
+pub struct Received {
+    /// Milliseconds since the unix epoch, host clock
+    pub timestamp_ms: u64,
+    pub source: SocketAddr,
+    pub msg: Message,
+    /// The raw message, as received
+    pub frame: Vec<u8>,
+}
+
+impl Received {
+    pub fn decode(buf: &[u8], source: SocketAddr) -> anyhow::Result<Self> {
+        let wire = WireMessage::new_checked(buf)
+            .map_err(|e| anyhow::anyhow!("Invalid message from {source}. {e:?}"))?;
+        let msg = Message::parse(&wire)
+            .map_err(|e| anyhow::anyhow!("Failed to parse message from {source}. {e:?}"))?;
+        Ok(Self {
+            timestamp_ms: unix_timestamp_ms(),
+            source,
+            msg,
+            frame: buf.to_vec(),
+        })
+    }
+
+    /// Key used to tell devices apart, the serial number is unique per MCU
+    pub fn device_key(&self) -> String {
+        format!("{:X}", self.msg.device_serial_number)
+    }
+
+    pub fn to_json(&self) -> Value {
+        let m = &self.msg;
+        json!({
+            "timestamp_ms": self.timestamp_ms,
+            "source": self.source.to_string(),
+            "device_id": m.device_id,
+            "device_serial_number": self.device_key(),
+            "sequence_number": m.sequence_number,
+            "uptime_seconds": m.uptime_seconds,
+            "status_flags": status_flags_str(&m.status_flags),
+            "datetime": datetime_str(m),
+            "temperature": m.temperature,
+            "humidity": m.humidity,
+            "voc_ticks": m.voc_ticks,
+            "voc_index": m.voc_index,
+            "nox_ticks": m.nox_ticks,
+            "nox_index": m.nox_index,
+            "pm2_5_atm": m.pm2_5_atm,
+            "co2": m.co2,
+        })
+    }
+}
+
+/// Temperature in degrees Celsius, the wire format is centi-degrees
+pub fn temperature_c(msg: &Message) -> f64 {
+    f64::from(msg.temperature) / 100.0
+}
+
+/// Relative humidity in percent, the wire format is centi-percent
+pub fn humidity_percent(msg: &Message) -> f64 {
+    f64::from(msg.humidity) / 100.0
+}
+
+/// The flags that are set, comma separated, e.g. `init,temp,hum`
+pub fn status_flags_str(flags: &StatusFlags) -> String {
+    [
+        (flags.initialized(), "init"),
+        (flags.datetime_valid(), "datetime"),
+        (flags.temperature_valid(), "temp"),
+        (flags.humidity_valid(), "hum"),
+        (flags.voc_ticks_valid(), "voc_ticks"),
+        (flags.voc_index_valid(), "voc_index"),
+    ]
+    .iter()
+    .filter(|(set, _)| *set)
+    .map(|(_, name)| *name)
+    .collect::<Vec<_>>()
+    .join(",")
+}
+
+/// ISO 8601 datetime, `None` when the device doesn't have the time yet
+pub fn datetime_str(msg: &Message) -> Option<String> {
+    if !msg.status_flags.datetime_valid() {
+        return None;
+    }
+    let dt = &msg.datetime;
+    Some(format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+        dt.year, dt.month, dt.day, dt.hour, dt.minute, dt.second
+    ))
+}
+
+pub fn unix_timestamp_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::config;
+    use std::net::Ipv4Addr;
+    use wire_protocols::{DateTime, DeviceSerialNumber, ProtocolVersion};
+
+    /// A message from device `serial` as received, decoded from its emitted frame
+    pub(crate) fn received(serial: u32, sequence_number: u32, uptime_seconds: u32) -> Received {
+        let mut status_flags = StatusFlags::empty();
+        status_flags.set_initialized(true);
+        status_flags.set_temperature_valid(true);
+        status_flags.set_humidity_valid(true);
+        let msg = Message {
+            protocol_version: ProtocolVersion::v1(),
+            firmware_version: config::FIRMWARE_VERSION,
+            device_id: 1,
+            device_serial_number: DeviceSerialNumber::new(serial, 0, 0),
+            sequence_number,
+            uptime_seconds,
+            status_flags,
+            datetime: DateTime::zero(),
+            temperature: 2150,
+            humidity: 4025,
+            voc_ticks: 0,
+            nox_ticks: 0,
+            voc_index: 0,
+            nox_index: 0,
+            pm2_5_atm: 0,
+            co2: 0,
+        };
+        let mut frame = vec![0; msg.message_len()];
+        msg.emit(&mut WireMessage::new_unchecked(&mut frame[..]));
+        let source = SocketAddr::from((Ipv4Addr::new(192, 168, 1, 10), 16000));
+        Received::decode(&frame, source).unwrap()
+    }
+
+    #[test]
+    fn decode_keeps_the_frame() {
+        let rx = received(0xAB, 7, 60);
+        assert_eq!(rx.msg.sequence_number, 7);
+        assert_eq!(rx.msg.uptime_seconds, 60);
+        assert_eq!(temperature_c(&rx.msg), 21.5);
+        assert_eq!(humidity_percent(&rx.msg), 40.25);
+        assert_eq!(status_flags_str(&rx.msg.status_flags), "init,temp,hum");
+        assert_eq!(datetime_str(&rx.msg), None);
+
+        let again = Received::decode(&rx.frame, rx.source).unwrap();
+        assert_eq!(again.frame, rx.frame);
+        assert_eq!(again.device_key(), rx.device_key());
+    }
+}
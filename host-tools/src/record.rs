@@ -0,0 +1,275 @@
+//! Per-device CSV recordings of the broadcast messages.
+//!
+//! Each row holds the decoded fields along with the raw frame (hex) so a
+//! recording can be replayed as-is.
+
+use crate::message::{self, Received};
+use anyhow::Context;
+use std::{
+    collections::HashMap,
+    fs::File,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+pub const HEADER: [&str; 12] = [
+    "timestamp_ms",
+    "source",
+    "device_id",
+    "device_serial_number",
+    "sequence_number",
+    "uptime_seconds",
+    "status_flags",
+    "temperature_c",
+    "humidity_percent",
+    "voc_ticks",
+    "voc_index",
+    "frame",
+];
+
+/// Column index of the raw frame
+const FRAME_COLUMN: usize = HEADER.len() - 1;
+
+struct DeviceFile {
+    writer: csv::Writer<File>,
+    rows: usize,
+}
+
+/// Writes each device's messages to its own CSV file, starting a new file every `max_rows`
+pub struct Recorder {
+    dir: PathBuf,
+    max_rows: usize,
+    files: HashMap<String, DeviceFile>,
+}
+
+impl Recorder {
+    pub fn new<P: AsRef<Path>>(dir: P, max_rows: usize) -> anyhow::Result<Self> {
+        let dir = dir.as_ref().to_path_buf();
+        std::fs::create_dir_all(&dir)
+            .with_context(|| format!("Failed to create directory {}", dir.display()))?;
+        Ok(Self {
+            dir,
+            max_rows: max_rows.max(1),
+            files: HashMap::new(),
+        })
+    }
+
+    pub fn record(&mut self, rx: &Received) -> anyhow::Result<()> {
+        let key = rx.device_key();
+        let needs_rotation = self
+            .files
+            .get(&key)
+            .map(|f| f.rows >= self.max_rows)
+            .unwrap_or(true);
+        if needs_rotation {
+            let path = self.dir.join(format!("{key}_{}.csv", rx.timestamp_ms));
+            let file = File::create(&path)
+                .with_context(|| format!("Failed to create {}", path.display()))?;
+            let mut writer = csv::Writer::from_writer(file);
+            writer.write_record(HEADER)?;
+            eprintln!("Recording {key} to {}", path.display());
+            self.files
+                .insert(key.clone(), DeviceFile { writer, rows: 0 });
+        }
+
+        let f = self.files.get_mut(&key).unwrap();
+        let m = &rx.msg;
+        f.writer.write_record(&[
+            rx.timestamp_ms.to_string(),
+            rx.source.to_string(),
+            m.device_id.to_string(),
+            key.clone(),
+            m.sequence_number.to_string(),
+            m.uptime_seconds.to_string(),
+            message::status_flags_str(&m.status_flags),
+            format!("{:.2}", message::temperature_c(m)),
+            format!("{:.2}", message::humidity_percent(m)),
+            m.voc_ticks.to_string(),
+            m.voc_index.to_string(),
+            to_hex(&rx.frame),
+        ])?;
+        // Keep the files usable if the recorder is killed
+        f.writer.flush()?;
+        f.rows += 1;
+        Ok(())
+    }
+}
+
+/// A recorded frame, as read back for replay
+#[derive(Clone, Debug)]
+pub struct RecordedFrame {
+    pub timestamp_ms: u64,
+    pub frame: Vec<u8>,
+}
+
+/// Read the frames of a recording
+pub fn read_frames<P: AsRef<Path>>(path: P) -> anyhow::Result<Vec<RecordedFrame>> {
+    let path = path.as_ref();
+    let mut reader = csv::Reader::from_path(path)
+        .with_context(|| format!("Failed to open {}", path.display()))?;
+    let mut frames = Vec::new();
+    for (row, record) in reader.records().enumerate() {
+        let record = record?;
+        let field = |idx: usize| {
+            record
+                .get(idx)
+                .with_context(|| format!("{}: row {row} is missing column {idx}", path.display()))
+        };
+        let timestamp_ms = field(0)?.parse()?;
+        let frame = from_hex(field(FRAME_COLUMN)?)
+            .with_context(|| format!("{}: row {row} has an invalid frame", path.display()))?;
+        frames.push(RecordedFrame {
+            timestamp_ms,
+            frame,
+        });
+    }
+    Ok(frames)
+}
+
+/// Merge the frames of several recordings in time order
+pub fn merge(recordings: Vec<Vec<RecordedFrame>>) -> Vec<RecordedFrame> {
+    let mut frames: Vec<_> = recordings.into_iter().flatten().collect();
+    frames.sort_by_key(|f| f.timestamp_ms);
+    frames
+}
+
+/// Delay before sending each frame, after the previous one, for replaying time ordered
+/// frames at `speed` times the recorded rate
+pub fn replay_delays(frames: &[RecordedFrame], speed: f64) -> impl Iterator<Item = Duration> + '_ {
+    let mut prev_timestamp_ms = frames.first().map(|f| f.timestamp_ms).unwrap_or(0);
+    frames.iter().map(move |f| {
+        let delay_ms = f.timestamp_ms.saturating_sub(prev_timestamp_ms) as f64 / speed;
+        prev_timestamp_ms = f.timestamp_ms;
+        Duration::from_secs_f64(delay_ms / 1000.0)
+    })
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+fn from_hex(s: &str) -> Option<Vec<u8>> {
+    if !s.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(s.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::message::tests::received;
+
+    /// An empty directory of its own for each test
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("host-tools-{}-{name}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        dir
+    }
+
+    fn recordings(dir: &Path) -> Vec<PathBuf> {
+        let mut paths: Vec<_> = std::fs::read_dir(dir)
+            .unwrap()
+            .map(|e| e.unwrap().path())
+            .collect();
+        paths.sort();
+        paths
+    }
+
+    fn at(timestamp_ms: u64, mut rx: Received) -> Received {
+        rx.timestamp_ms = timestamp_ms;
+        rx
+    }
+
+    #[test]
+    fn hex_round_trip() {
+        let bytes = [0x00, 0x01, 0x7F, 0x80, 0xAB, 0xFF];
+        assert_eq!(to_hex(&bytes), "00017f80abff");
+        assert_eq!(from_hex(&to_hex(&bytes)).unwrap(), bytes);
+        assert_eq!(from_hex("ABff").unwrap(), [0xAB, 0xFF]);
+        assert_eq!(from_hex(""), Some(Vec::new()));
+        assert_eq!(from_hex("abc"), None);
+        assert_eq!(from_hex("zz"), None);
+    }
+
+    #[test]
+    fn csv_round_trip() {
+        let dir = test_dir("csv-round-trip");
+        let sent = [
+            at(1_000, received(1, 10, 100)),
+            at(6_000, received(1, 11, 105)),
+            at(11_000, received(1, 12, 110)),
+        ];
+        let mut recorder = Recorder::new(&dir, 100).unwrap();
+        for rx in sent.iter() {
+            recorder.record(rx).unwrap();
+        }
+
+        let paths = recordings(&dir);
+        assert_eq!(paths.len(), 1);
+        let mut reader = csv::Reader::from_path(&paths[0]).unwrap();
+        assert_eq!(reader.headers().unwrap(), HEADER.as_slice());
+        let first = reader.records().next().unwrap().unwrap();
+        assert_eq!(&first[2], "1");
+        assert_eq!(&first[4], "10");
+        assert_eq!(&first[6], "init,temp,hum");
+        assert_eq!(&first[7], "21.50");
+        assert_eq!(&first[8], "40.25");
+
+        let frames = read_frames(&paths[0]).unwrap();
+        assert_eq!(frames.len(), sent.len());
+        for (frame, rx) in frames.iter().zip(sent.iter()) {
+            assert_eq!(frame.timestamp_ms, rx.timestamp_ms);
+            assert_eq!(frame.frame, rx.frame);
+            let decoded = Received::decode(&frame.frame, rx.source).unwrap();
+            assert_eq!(decoded.msg.sequence_number, rx.msg.sequence_number);
+        }
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn files_rotate_per_device() {
+        let dir = test_dir("rotate");
+        let mut recorder = Recorder::new(&dir, 2).unwrap();
+        recorder.record(&at(1_000, received(1, 10, 100))).unwrap();
+        recorder.record(&at(1_500, received(2, 70, 900))).unwrap();
+        recorder.record(&at(6_000, received(1, 11, 105))).unwrap();
+        recorder.record(&at(11_000, received(1, 12, 110))).unwrap();
+
+        let rows: Vec<_> = recordings(&dir)
+            .iter()
+            .map(|p| read_frames(p).unwrap().len())
+            .collect();
+        // Device 1 in two files, the second starting at 11000, and device 2
+        assert_eq!(rows.len(), 3);
+        assert_eq!(rows.iter().sum::<usize>(), 4);
+        assert!(rows.iter().all(|n| *n <= 2));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn replay_in_time_order() {
+        let frame = |timestamp_ms, seq| RecordedFrame {
+            timestamp_ms,
+            frame: received(1, seq, seq * 5).frame,
+        };
+        let frames = merge(vec![
+            vec![frame(1_000, 0), frame(6_000, 1), frame(16_000, 3)],
+            vec![frame(500, 7), frame(11_000, 2)],
+        ]);
+        let timestamps: Vec<_> = frames.iter().map(|f| f.timestamp_ms).collect();
+        assert_eq!(timestamps, [500, 1_000, 6_000, 11_000, 16_000]);
+
+        let delays: Vec<_> = replay_delays(&frames, 1.0).collect();
+        assert_eq!(
+            delays,
+            [0, 500, 5_000, 5_000, 5_000].map(Duration::from_millis)
+        );
+        let delays: Vec<_> = replay_delays(&frames, 10.0).collect();
+        assert_eq!(delays, [0, 50, 500, 500, 500].map(Duration::from_millis));
+        assert_eq!(replay_delays(&[], 1.0).count(), 0);
+    }
+}
@@ -0,0 +1,190 @@
+use crate::message::Received;
+use std::collections::BTreeMap;
+
+/// What a message says about the device's stream, relative to the previous one
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum Event {
+    /// First message seen from the device
+    New,
+    InOrder,
+    /// Some messages were missed, `missed` is the number of skipped sequence numbers
+    Gap {
+        missed: u32,
+    },
+    /// Duplicate or reordered, not newer than the last message
+    Stale,
+    /// The uptime went backwards, the device restarted
+    UptimeReset,
+}
+
+/// Per-device stream statistics
+#[derive(Clone, Debug)]
+pub struct DeviceStats {
+    pub last: Received,
+    pub received: u64,
+    pub missed: u64,
+    pub stale: u64,
+    pub uptime_resets: u64,
+}
+
+impl DeviceStats {
+    fn new(rx: Received) -> Self {
+        Self {
+            last: rx,
+            received: 1,
+            missed: 0,
+            stale: 0,
+            uptime_resets: 0,
+        }
+    }
+
+    fn update(&mut self, rx: Received) -> Event {
+        self.received += 1;
+
+        let prev = &self.last.msg;
+        let event = if rx.msg.uptime_seconds < prev.uptime_seconds {
+            // The sequence number restarts along with the uptime, not a gap
+            self.uptime_resets += 1;
+            Event::UptimeReset
+        } else {
+            let delta = rx.msg.sequence_number.wrapping_sub(prev.sequence_number);
+            if delta == 0 || delta > u32::MAX / 2 {
+                self.stale += 1;
+                Event::Stale
+            } else if delta == 1 {
+                Event::InOrder
+            } else {
+                self.missed += u64::from(delta - 1);
+                Event::Gap { missed: delta - 1 }
+            }
+        };
+
+        if event != Event::Stale {
+            self.last = rx;
+        }
+        event
+    }
+}
+
+/// Tracks the stream of every device, keyed by serial number
+#[derive(Clone, Debug, Default)]
+pub struct Tracker {
+    devices: BTreeMap<String, DeviceStats>,
+}
+
+impl Tracker {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn update(&mut self, rx: Received) -> Event {
+        let key = rx.device_key();
+        match self.devices.get_mut(&key) {
+            Some(stats) => stats.update(rx),
+            None => {
+                self.devices.insert(key, DeviceStats::new(rx));
+                Event::New
+            }
+        }
+    }
+
+    pub fn devices(&self) -> impl Iterator<Item = (&str, &DeviceStats)> {
+        self.devices.iter().map(|(k, v)| (k.as_str(), v))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::message::tests::received;
+
+    fn stats(tracker: &Tracker, rx: &Received) -> DeviceStats {
+        let key = rx.device_key();
+        tracker
+            .devices()
+            .find(|(k, _)| *k == key)
+            .map(|(_, s)| s.clone())
+            .unwrap()
+    }
+
+    #[test]
+    fn in_order_and_gaps() {
+        let mut tracker = Tracker::new();
+        assert_eq!(tracker.update(received(1, 10, 100)), Event::New);
+        assert_eq!(tracker.update(received(1, 11, 105)), Event::InOrder);
+        assert_eq!(
+            tracker.update(received(1, 14, 120)),
+            Event::Gap { missed: 2 }
+        );
+        assert_eq!(tracker.update(received(1, 15, 125)), Event::InOrder);
+
+        let s = stats(&tracker, &received(1, 0, 0));
+        assert_eq!(s.received, 4);
+        assert_eq!(s.missed, 2);
+        assert_eq!(s.stale, 0);
+        assert_eq!(s.last.msg.sequence_number, 15);
+    }
+
+    #[test]
+    fn duplicates_and_reordered_are_stale() {
+        let mut tracker = Tracker::new();
+        tracker.update(received(1, 10, 100));
+        tracker.update(received(1, 12, 110));
+        assert_eq!(tracker.update(received(1, 12, 110)), Event::Stale);
+        assert_eq!(tracker.update(received(1, 11, 110)), Event::Stale);
+        // Compared with the last message that wasn't stale
+        assert_eq!(tracker.update(received(1, 13, 115)), Event::InOrder);
+
+        let s = stats(&tracker, &received(1, 0, 0));
+        assert_eq!(s.received, 5);
+        assert_eq!(s.missed, 1);
+        assert_eq!(s.stale, 2);
+    }
+
+    #[test]
+    fn uptime_reset_is_not_a_gap() {
+        let mut tracker = Tracker::new();
+        tracker.update(received(1, 500, 2500));
+        assert_eq!(tracker.update(received(1, 0, 5)), Event::UptimeReset);
+        assert_eq!(tracker.update(received(1, 1, 10)), Event::InOrder);
+
+        let s = stats(&tracker, &received(1, 0, 0));
+        assert_eq!(s.uptime_resets, 1);
+        assert_eq!(s.missed, 0);
+        assert_eq!(s.stale, 0);
+    }
+
+    #[test]
+    fn sequence_number_wraps() {
+        let mut tracker = Tracker::new();
+        tracker.update(received(1, u32::MAX - 1, 100));
+        assert_eq!(tracker.update(received(1, u32::MAX, 105)), Event::InOrder);
+        assert_eq!(tracker.update(received(1, 0, 110)), Event::InOrder);
+        assert_eq!(
+            tracker.update(received(1, 3, 125)),
+            Event::Gap { missed: 2 }
+        );
+        // Just behind across the wrap
+        assert_eq!(tracker.update(received(1, u32::MAX, 125)), Event::Stale);
+
+        let s = stats(&tracker, &received(1, 0, 0));
+        assert_eq!(s.missed, 2);
+        assert_eq!(s.stale, 1);
+        assert_eq!(s.last.msg.sequence_number, 3);
+    }
+
+    #[test]
+    fn devices_are_tracked_separately() {
+        let mut tracker = Tracker::new();
+        assert_eq!(tracker.update(received(1, 10, 100)), Event::New);
+        assert_eq!(tracker.update(received(2, 50, 300)), Event::New);
+        assert_eq!(tracker.update(received(1, 11, 105)), Event::InOrder);
+        assert_eq!(
+            tracker.update(received(2, 53, 315)),
+            Event::Gap { missed: 2 }
+        );
+        assert_eq!(tracker.devices().count(), 2);
+        assert_eq!(stats(&tracker, &received(1, 0, 0)).missed, 0);
+        assert_eq!(stats(&tracker, &received(2, 0, 0)).missed, 2);
+    }
+}
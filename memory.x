@@ -1,6 +1,7 @@
 MEMORY
 {
     /* NOTE K = KiBi = 1024 bytes */
-    FLASH : ORIGIN = 0x08000000, LENGTH = 512K 
+    /* The last 128K sector is reserved for the flash store, see flash_store.rs */
+    FLASH : ORIGIN = 0x08000000, LENGTH = 384K
     RAM : ORIGIN = 0x20000000, LENGTH = 128K
 }
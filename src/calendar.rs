@@ -0,0 +1,143 @@
+//! Conversions between unix time and the UTC calendar.
+
+use core::fmt;
+use wire_protocols::DateTime;
+
+/// UTC calendar date and time
+#[derive(Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Debug)]
+pub struct UtcDateTime {
+    pub year: u16,
+    pub month: u8,
+    pub day: u8,
+    /// ISO weekday, Monday is 1
+    pub weekday: u8,
+    pub hour: u8,
+    pub minute: u8,
+    pub second: u8,
+}
+
+impl UtcDateTime {
+    pub fn from_unix(unix_secs: u64) -> Self {
+        let days = unix_secs / 86_400;
+        let secs_of_day = (unix_secs % 86_400) as u32;
+        let (year, month, day) = civil_from_days(days);
+        UtcDateTime {
+            year,
+            month,
+            day,
+            // 1970-01-01 was a Thursday
+            weekday: ((days + 3) % 7 + 1) as u8,
+            hour: (secs_of_day / 3_600) as u8,
+            minute: ((secs_of_day / 60) % 60) as u8,
+            second: (secs_of_day % 60) as u8,
+        }
+    }
+}
+
+impl fmt::Display for UtcDateTime {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+            self.year, self.month, self.day, self.hour, self.minute, self.second
+        )
+    }
+}
+
+impl From<UtcDateTime> for DateTime {
+    fn from(value: UtcDateTime) -> Self {
+        DateTime {
+            year: value.year,
+            month: value.month,
+            day: value.day,
+            hour: value.hour,
+            minute: value.minute,
+            second: value.second,
+        }
+    }
+}
+
+// http://howardhinnant.github.io/date_algorithms.html
+fn civil_from_days(days: u64) -> (u16, u8, u8) {
+    let z = days + 719_468;
+    let era = z / 146_097;
+    let doe = z % 146_097;
+    let yoe = (doe - doe / 1_460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + u64::from(month <= 2);
+    (year as u16, month as u8, day as u8)
+}
+
+pub fn days_from_civil(year: u32, month: u32, day: u32) -> u64 {
+    let year = u64::from(if month <= 2 { year - 1 } else { year });
+    let era = year / 400;
+    let yoe = year - era * 400;
+    let mp = u64::from(if month > 2 { month - 3 } else { month + 9 });
+    let doy = (153 * mp + 2) / 5 + u64::from(day) - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146_097 + doe - 719_468
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn civil_round_trips() {
+        for (days, date) in [
+            (0, (1970, 1, 1)),
+            (11_016, (2000, 2, 29)),
+            (24_855, (2038, 1, 19)),
+            (47_540, (2100, 2, 28)),
+            (47_541, (2100, 3, 1)),
+        ] {
+            assert_eq!(civil_from_days(days), date);
+            let (year, month, day) = date;
+            assert_eq!(days_from_civil(year.into(), month.into(), day.into()), days);
+        }
+    }
+
+    #[test]
+    fn every_day_round_trips() {
+        for days in 0..100_000 {
+            let (year, month, day) = civil_from_days(days);
+            assert_eq!(days_from_civil(year.into(), month.into(), day.into()), days);
+        }
+    }
+
+    #[test]
+    fn from_unix() {
+        assert_eq!(
+            UtcDateTime::from_unix(2_147_483_647),
+            UtcDateTime {
+                year: 2038,
+                month: 1,
+                day: 19,
+                weekday: 2,
+                hour: 3,
+                minute: 14,
+                second: 7,
+            }
+        );
+        // Thursday, Tuesday, Sunday, Monday
+        for (unix_secs, weekday) in [
+            (0, 4),
+            (11_016 * 86_400, 2),
+            (47_540 * 86_400 + 86_399, 7),
+            (47_541 * 86_400, 1),
+        ] {
+            assert_eq!(UtcDateTime::from_unix(unix_secs).weekday, weekday);
+        }
+    }
+
+    #[test]
+    fn display() {
+        assert_eq!(
+            std::format!("{}", UtcDateTime::from_unix(951_782_400 + 3_723)),
+            "2000-02-29T01:02:03Z"
+        );
+    }
+}
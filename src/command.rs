@@ -0,0 +1,356 @@
+//! Text commands, one per line, for runtime configuration and diagnostics.
+//! Shared by the UDP command port and the serial shell. The command port is
+//! unauthenticated, it only accepts the commands that show something, and the
+//! calibration commands.
+//!
+//! ```text
+//! status                       show the uptime, time, network status
+//! net                          show the network statistics
+//! measurement                  show the last (calibrated) BME680 measurement
+//! sensor reinit                recover the I2C bus and re-initialize the BME680
+//! sensor heater <temp C> <ms> <ambient C|measured>
+//!                              set the gas heater profile, not saved
+//! sensor heater off            disable the gas measurement, not saved
+//! log                          show the log levels
+//! log <level>                  set the default log level, off..trace
+//! log <module> <level|default> set a module's log level, e.g. `log net::eth warn`
+//! log syslog <level>           set the minimum level sent to the syslog collector
+//!                              log levels apply immediately, `config save` persists them
+//! config                       show the settings
+//! config set <key> <value>     change a setting, keys as shown by `config`, except
+//!                              calibration and log which have their own commands,
+//!                              mac, ip_mode, ip and gateway apply after a reboot
+//! config save                  store the settings in flash
+//! config reset                 restore the compiled defaults, not saved
+//! cal                          show the calibration
+//! cal temp <offset C> <gain>   set the temperature correction
+//! cal hum <offset %> <gain>    set the humidity correction
+//! cal save                     same as `config save`
+//! cal reset                    restore the compiled calibration, not saved
+//! reboot                       restart, applies the interface settings
+//! ```
+
+use crate::{
+    app::bme680_task,
+    config,
+    flash_store::FlashStore,
+    logger::{self, LogLevels},
+    net::{
+        eth::{ErrorCounters as EthErrorCounters, LinkStatus},
+        ip_config::Mode as IpConfigMode,
+        stats::STATISTICS,
+        syslog, IpConfig,
+    },
+    sensors::{
+        bme680::{AmbientTemperatureSource, HeaterProfile, Measurement},
+        calibration::Channel,
+    },
+    settings::Settings,
+    tasks::{bme680::SpawnArg as Bme680SpawnArg, unsupervised},
+    time_service::TimeService,
+    util,
+};
+use core::fmt::{self, Write};
+use cortex_m::peripheral::SCB;
+use log::{info, warn, LevelFilter};
+use smoltcp::wire::{EthernetAddress, Ipv4Address, Ipv4Cidr};
+
+/// Longest accepted command line
+pub const MAX_LINE_LEN: usize = 128;
+
+/// What the commands operate on
+pub struct Context<'a> {
+    pub settings: &'a mut Settings,
+    pub flash_store: &'a mut FlashStore,
+    pub time: &'a mut TimeService,
+    pub ip_config: &'a IpConfig,
+    pub link: LinkStatus,
+    pub eth_errors: EthErrorCounters,
+    pub last_measurement: Option<Measurement>,
+    /// False for the UDP command port, see `read_only`
+    pub allow_write: bool,
+}
+
+/// Execute a command line, writing the response to `out`
+pub fn execute<W: Write>(line: &str, ctx: &mut Context, out: &mut W) -> fmt::Result {
+    let mut args = line.split_whitespace();
+    let Some(cmd) = args.next() else {
+        return Ok(());
+    };
+    if !ctx.allow_write && !read_only(cmd, args.clone().next()) {
+        return writeln!(
+            out,
+            "error: '{cmd}' isn't accepted on the command port, use the serial shell"
+        );
+    }
+    match cmd {
+        "status" => status(ctx, out),
+        "net" => writeln!(out, "{}", STATISTICS.snapshot(&ctx.eth_errors)),
+        "measurement" => match ctx.last_measurement {
+            Some(m) => writeln!(out, "{m}"),
+            None => writeln!(out, "no measurement yet"),
+        },
+        "sensor" => {
+            let arg = match args.next() {
+                Some("reinit") => Bme680SpawnArg::Reinit,
+                Some("heater") => match heater_profile(&mut args) {
+                    Some(profile) => Bme680SpawnArg::SetHeater(profile),
+                    None => {
+                        return writeln!(
+                            out,
+                            "error: usage: sensor heater <temp C> <ms> <ambient C|measured>|off"
+                        )
+                    }
+                },
+                _ => return writeln!(out, "error: usage: sensor reinit|heater"),
+            };
+            match bme680_task::spawn(arg) {
+                Ok(()) => writeln!(out, "ok"),
+                Err(_) => writeln!(out, "error: busy"),
+            }
+        }
+        "log" => log_levels(&mut args, ctx, out),
+        "config" => settings(&mut args, ctx, out),
+        "cal" => calibration(&mut args, ctx, out),
+        "reboot" => {
+            warn!("CMD: rebooting");
+            SCB::sys_reset()
+        }
+        "help" => writeln!(
+            out,
+            "commands: status, net, measurement, sensor, log, config, cal, reboot, help"
+        ),
+        _ => writeln!(out, "error: unknown command '{cmd}'"),
+    }
+}
+
+/// Commands that don't change the settings or the device's state, except for the
+/// calibration which is also set over the network
+fn read_only(cmd: &str, sub: Option<&str>) -> bool {
+    match cmd {
+        "status" | "net" | "measurement" | "help" | "cal" => true,
+        "log" | "config" => sub.is_none(),
+        _ => false,
+    }
+}
+
+fn status<W: Write>(ctx: &mut Context, out: &mut W) -> fmt::Result {
+    writeln!(out, "firmware: {}", config::FIRMWARE_VERSION)?;
+    writeln!(
+        out,
+        "serial number: {:X}",
+        util::read_device_serial_number()
+    )?;
+    writeln!(out, "uptime: {} s", ctx.time.uptime_seconds())?;
+    match ctx.time.utc() {
+        Some(utc) => writeln!(out, "time: {utc}")?,
+        None => writeln!(out, "time: not set")?,
+    }
+    writeln!(out, "log dropped: {} bytes", logger::dropped())?;
+    writeln!(out, "syslog dropped: {} bytes", syslog::dropped())?;
+    writeln!(
+        out,
+        "link: {} (ups {}, downs {})",
+        if ctx.link.up { "up" } else { "down" },
+        ctx.link.ups,
+        ctx.link.downs
+    )?;
+    writeln!(out, "eth errors: {}", ctx.eth_errors)?;
+    writeln!(out, "ip mode: {:?}", ctx.ip_config.mode())?;
+    match ctx.ip_config.address() {
+        Some(addr) if ctx.ip_config.leased() => writeln!(out, "ip: {addr} (leased)"),
+        Some(addr) => writeln!(out, "ip: {addr}"),
+        None => writeln!(out, "ip: none"),
+    }
+}
+
+/// `<temp C> <ms> <ambient C|measured>` or `off`, within the sensor's limits
+fn heater_profile<'l, A: Iterator<Item = &'l str>>(args: &mut A) -> Option<Option<HeaterProfile>> {
+    let target_temperature = match args.next()? {
+        "off" => return Some(None),
+        t => t.parse::<u16>().ok().filter(|t| (1..=400).contains(t))?,
+    };
+    let duration_ms = args
+        .next()?
+        .parse::<u16>()
+        .ok()
+        .filter(|d| (1..=4032).contains(d))?;
+    let ambient_temperature = match args.next()? {
+        "measured" => AmbientTemperatureSource::Measured,
+        t => AmbientTemperatureSource::Fixed(t.parse().ok()?),
+    };
+    Some(Some(HeaterProfile {
+        target_temperature,
+        duration_ms,
+        ambient_temperature,
+    }))
+}
+
+fn log_levels<'l, W: Write, A: Iterator<Item = &'l str>>(
+    args: &mut A,
+    ctx: &mut Context,
+    out: &mut W,
+) -> fmt::Result {
+    let levels = &mut ctx.settings.log_levels;
+    match (args.next(), args.next()) {
+        (None, _) => return writeln!(out, "{levels}"),
+        (Some(level), None) => match level.parse::<LevelFilter>() {
+            Ok(level) => levels.default = level,
+            Err(_) => return writeln!(out, "error: invalid level '{level}'"),
+        },
+        (Some("syslog"), Some(level)) => match level.parse::<LevelFilter>() {
+            Ok(level) => levels.syslog = level,
+            Err(_) => return writeln!(out, "error: invalid level '{level}'"),
+        },
+        (Some(module), Some(level)) => {
+            let Some(idx) = LogLevels::module_index(module) else {
+                return writeln!(
+                    out,
+                    "error: unknown module '{module}', one of {:?}",
+                    logger::MODULES
+                );
+            };
+            levels.modules[idx] = match level {
+                "default" => None,
+                _ => match level.parse::<LevelFilter>() {
+                    Ok(level) => Some(level),
+                    Err(_) => return writeln!(out, "error: invalid level '{level}'"),
+                },
+            };
+        }
+    }
+    logger::apply_levels(&ctx.settings.log_levels, ctx.settings.syslog_collector());
+    let levels = &ctx.settings.log_levels;
+    info!("CMD: log levels {levels}");
+    writeln!(out, "{levels}")
+}
+
+fn settings<'l, W: Write, A: Iterator<Item = &'l str>>(
+    args: &mut A,
+    ctx: &mut Context,
+    out: &mut W,
+) -> fmt::Result {
+    match args.next() {
+        None => writeln!(out, "{}", ctx.settings),
+        Some("set") => {
+            let (key, value) = match (args.next(), args.next()) {
+                (Some(k), Some(v)) => (k, v),
+                _ => return writeln!(out, "error: usage: config set <key> <value>"),
+            };
+            if set(ctx.settings, key, value).is_none() {
+                return writeln!(out, "error: invalid value '{value}' for '{key}'");
+            }
+            // The syslog collector enables the syslog level
+            logger::apply_levels(&ctx.settings.log_levels, ctx.settings.syslog_collector());
+            info!("CMD: {key} = {value}");
+            writeln!(out, "ok, {key} applies {}", applies(key))
+        }
+        Some("save") => save(ctx, out),
+        Some("reset") => {
+            *ctx.settings = config::DEFAULT_SETTINGS;
+            logger::apply_levels(&ctx.settings.log_levels, ctx.settings.syslog_collector());
+            info!("CMD: settings reset");
+            writeln!(out, "{}", ctx.settings)
+        }
+        Some(sub) => writeln!(out, "error: unknown config command '{sub}'"),
+    }
+}
+
+/// When a setting changed with `config set` takes effect, the interface
+/// addresses are only set up at boot
+fn applies(key: &str) -> &'static str {
+    match key {
+        "mac" | "ip_mode" | "ip" | "gateway" => "after a save and reboot",
+        "bcast_interval" => "from the next broadcast",
+        "sntp_server" | "sntp_interval" => "from the next sync",
+        _ => "now",
+    }
+}
+
+/// Returns `None` for unknown keys or invalid values
+fn set(settings: &mut Settings, key: &str, value: &str) -> Option<()> {
+    match key {
+        "device_id" => settings.device_id = value.parse().ok()?,
+        "mac" => {
+            let mac = value.parse::<EthernetAddress>().ok()?;
+            if !mac.is_unicast() {
+                return None;
+            }
+            settings.mac_address = mac.0;
+        }
+        "ip_mode" => {
+            settings.ip_config_mode = match value {
+                "static" | "Static" => IpConfigMode::Static,
+                "dhcp" | "Dhcp" => IpConfigMode::Dhcp,
+                _ => return None,
+            }
+        }
+        "ip" => settings.static_ip_cidr = value.parse::<Ipv4Cidr>().ok()?,
+        "gateway" => settings.static_gateway = value.parse::<Ipv4Address>().ok()?,
+        "bcast_addr" => settings.broadcast_address = value.parse::<Ipv4Address>().ok()?,
+        "bcast_port" => settings.broadcast_port = value.parse().ok()?,
+        "bcast_interval" => {
+            let prev = settings.bcast_interval_sec;
+            settings.bcast_interval_sec = value.parse().ok()?;
+            if !settings.bcast_interval_valid() {
+                settings.bcast_interval_sec = prev;
+                return None;
+            }
+        }
+        "syslog_addr" => settings.syslog_address = value.parse::<Ipv4Address>().ok()?,
+        "syslog_port" => settings.syslog_port = value.parse().ok().filter(|p| *p != 0)?,
+        "sntp_server" => settings.sntp_server_address = value.parse::<Ipv4Address>().ok()?,
+        "sntp_interval" => {
+            settings.sntp_sync_interval_sec = value.parse().ok().filter(|v| *v != 0)?
+        }
+        _ => return None,
+    }
+    Some(())
+}
+
+fn save<W: Write>(ctx: &mut Context, out: &mut W) -> fmt::Result {
+    match unsupervised(|| ctx.flash_store.store_settings(ctx.settings)) {
+        Ok(()) => {
+            info!("CMD: settings saved");
+            writeln!(out, "ok")
+        }
+        Err(e) => writeln!(out, "error: flash write failed. {e:?}"),
+    }
+}
+
+fn calibration<'l, W: Write, A: Iterator<Item = &'l str>>(
+    args: &mut A,
+    ctx: &mut Context,
+    out: &mut W,
+) -> fmt::Result {
+    match args.next() {
+        None => writeln!(out, "{}", ctx.settings.calibration),
+        Some(channel @ ("temp" | "hum")) => {
+            let offset = args.next().and_then(|a| a.parse::<f32>().ok());
+            let gain = args.next().and_then(|a| a.parse::<f32>().ok());
+            let (offset, gain) = match (offset, gain) {
+                (Some(o), Some(g)) if g.is_normal() => (o, g),
+                _ => return writeln!(out, "error: usage: cal {channel} <offset> <gain>"),
+            };
+            let c = Channel {
+                offset: (offset * 100.0) as i32,
+                gain,
+            };
+            let calibration = &mut ctx.settings.calibration;
+            if channel == "temp" {
+                calibration.temperature = c;
+            } else {
+                calibration.humidity = c;
+            }
+            info!("CMD: calibration {}", calibration);
+            writeln!(out, "{}", calibration)
+        }
+        Some("save") => save(ctx, out),
+        Some("reset") => {
+            ctx.settings.calibration = config::BME680_CALIBRATION;
+            info!("CMD: calibration reset {}", ctx.settings.calibration);
+            writeln!(out, "{}", ctx.settings.calibration)
+        }
+        Some(sub) => writeln!(out, "error: unknown calibration command '{sub}'"),
+    }
+}
@@ -1,5 +1,16 @@
+use crate::{
+    logger::{LogFormat, LogLevels, MODULES as LOG_MODULES},
+    net::{diagnostics, ip_config::Mode as IpConfigMode},
+    sensors::{
+        bme680::HeaterProfile,
+        calibration::{Calibration, Channel},
+    },
+    settings::Settings,
+};
 use smoltcp::wire::{Ipv4Address, Ipv4Cidr};
 
+// The network identity, addresses and intervals are the compiled defaults of
+// `Settings`, use those at runtime
 pub use self::generated_confg::*;
 mod generated_confg {
     include!(concat!(env!("OUT_DIR"), "/env_config.rs"));
@@ -7,17 +18,141 @@ mod generated_confg {
 
 pub const IP_CIDR: Ipv4Cidr = Ipv4Cidr::new(Ipv4Address(IP_ADDRESS), 24);
 
-pub const SOCKET_BUFFER_LEN: usize = wire_protocols::broadcast::MESSAGE_LEN * 4;
+/// Default route, assumed to be the .1 host of the /24
+pub const IP_GATEWAY: Ipv4Address = Ipv4Address([IP_ADDRESS[0], IP_ADDRESS[1], IP_ADDRESS[2], 1]);
+
+/// Static so units keep their env-config IP_ADDRESS, DHCP is enabled with
+/// `config set ip_mode dhcp`
+pub const IP_CONFIG_MODE: IpConfigMode = IpConfigMode::Static;
+
+/// Use the static address if no DHCP lease is obtained within this time
+pub const DHCP_FALLBACK_TIMEOUT_SEC: u32 = 30;
+
+pub const SOCKET_BUFFER_LEN: usize = {
+    let bcast_len = wire_protocols::broadcast::MESSAGE_LEN * 4;
+    if bcast_len > diagnostics::MAX_MESSAGE_LEN {
+        bcast_len
+    } else {
+        diagnostics::MAX_MESSAGE_LEN
+    }
+};
 
 pub const STARTUP_DELAY_SECONDS: u8 = 5;
 
+/// Log levels used when no settings are stored in flash, overrides are indexed like
+/// `logger::MODULES`
+pub const LOG_LEVELS: LogLevels = LogLevels {
+    default: log::LevelFilter::Info,
+    modules: [None; LOG_MODULES.len()],
+    syslog: log::LevelFilter::Warn,
+};
+
+/// No collector by default, set with `config set syslog_addr`
+pub const SYSLOG_ADDRESS: Ipv4Address = Ipv4Address::UNSPECIFIED;
+pub const SYSLOG_PORT: u16 = 514;
+
+/// local0
+pub const SYSLOG_FACILITY: u8 = 16;
+
+/// Records waiting to be sent to the collector, a power of two. Records are dropped when it's full.
+pub const SYSLOG_BUFFER_LEN: usize = 2048;
+
+/// Records beyond this rate wait in the buffer
+pub const SYSLOG_MAX_MESSAGES_PER_SEC: u32 = 10;
+pub const SYSLOG_POLL_INTERVAL_MS: u32 = 50;
+
+/// Log record prefix, after the level marker
+pub const LOG_FORMAT: LogFormat = LogFormat {
+    uptime: true,
+    utc: true,
+    location: false,
+};
+
+/// Log output waiting for the UART, a power of two. Bytes are dropped when it's full.
+pub const LOG_BUFFER_LEN: usize = 4096;
+
+/// Longer log records are truncated
+pub const LOG_RECORD_MAX_LEN: usize = 256;
+
+/// How often the network statistics are logged and sent as a diagnostic message
+pub const NET_STATS_INTERVAL_SEC: u32 = 300;
+
+/// How often the ENC28J60 status registers are checked
+pub const ETH_HEALTH_CHECK_INTERVAL_MS: u32 = 1000;
+
+/// The ENC28J60 is reset when receiving or transmitting keeps failing for this long
+pub const ETH_STUCK_TIMEOUT_MS: u32 = 10_000;
+
+/// Upper bound of the initialization retry backoff, while the ENC28J60 doesn't come back
+/// after a reset
+pub const ETH_MAX_INIT_RETRY_INTERVAL_MS: u32 = 60_000;
+
 pub const WATCHDOG_RESET_PERIOD_MS: u32 = 8000;
 pub const WATCHDOG_TASK_INTERVAL_MS: u32 = 1000;
 
+/// The watchdog is no longer fed once a supervised task hasn't run within its deadline.
+/// The BME680 task can back off for up to `BME680_MAX_RETRY_INTERVAL_MS`.
+pub const BME680_TASK_DEADLINE_MS: u32 = BME680_MAX_RETRY_INTERVAL_MS + 5_000;
+pub const DATA_MANAGER_TASK_DEADLINE_MS: u32 = BCAST_MAX_INTERVAL_SEC * 1000 + 5_000;
+pub const IPSTACK_POLL_TASK_DEADLINE_MS: u32 = 1000;
+
 pub const BME680_MEASUREMENT_INTERVAL_MS: u32 = 2500;
 
+pub const BME680_I2C_FREQ_KHZ: u32 = 100;
+
+/// Number of consecutive measurement failures before the sensor is reported
+/// as faulted and the I2C bus is recovered
+pub const BME680_FAULT_THRESHOLD: u32 = 3;
+
+/// Upper bound of the exponential measurement retry backoff
+pub const BME680_MAX_RETRY_INTERVAL_MS: u32 = 60_000;
+
+/// Gas heater profile applied at startup, `None` disables the gas measurement.
+/// Set with the BME680_HEATER_* environment variables, see `build.rs`,
+/// 320 C for 150 ms with the measured ambient temperature by default.
+pub const BME680_HEATER_PROFILE: Option<HeaterProfile> =
+    include!(concat!(env!("OUT_DIR"), "/bme680_heater_profile.rs"));
+
+/// Temperature/humidity correction used when no settings are stored in flash
+pub const BME680_CALIBRATION: Calibration = Calibration {
+    temperature: Channel::IDENTITY,
+    humidity: Channel::IDENTITY,
+};
+
 /// Number of BCAST_INTERVAL_SEC cycles to wait before starting to send
 /// broadcast protocol messages
 pub const DATA_MANAGER_WARM_UP_PERIOD_CYCLES: u32 = 24;
 
+/// Fields not updated within this many seconds have their status flag cleared
+pub const DATA_MANAGER_MAX_DATA_AGE_SEC: u32 = 30;
+
 pub const BCAST_INTERVAL_SEC: u32 = 5;
+
+/// Upper bound of the runtime broadcast interval setting
+pub const BCAST_MAX_INTERVAL_SEC: u32 = Settings::MAX_BCAST_INTERVAL_SEC;
+
+/// time.cloudflare.com
+pub const SNTP_SERVER_ADDRESS: [u8; 4] = [162, 159, 200, 123];
+pub const SNTP_SYNC_INTERVAL_SEC: u32 = 60 * 60;
+pub const SNTP_RETRY_INTERVAL_SEC: u32 = 30;
+pub const SNTP_RESPONSE_TIMEOUT_MS: u32 = 2000;
+
+pub const COMMAND_POLL_INTERVAL_MS: u32 = 100;
+
+/// Settings used when none are stored in flash
+pub const DEFAULT_SETTINGS: Settings = Settings {
+    device_id: DEVICE_ID,
+    mac_address: MAC_ADDRESS,
+    ip_config_mode: IP_CONFIG_MODE,
+    static_ip_cidr: IP_CIDR,
+    static_gateway: IP_GATEWAY,
+    broadcast_address: Ipv4Address(BROADCAST_ADDRESS),
+    broadcast_port: BROADCAST_PORT,
+    bcast_interval_sec: BCAST_INTERVAL_SEC,
+    sntp_server_address: Ipv4Address(SNTP_SERVER_ADDRESS),
+    sntp_sync_interval_sec: SNTP_SYNC_INTERVAL_SEC,
+    calibration: BME680_CALIBRATION,
+    log_levels: LOG_LEVELS,
+    syslog_address: SYSLOG_ADDRESS,
+    syslog_port: SYSLOG_PORT,
+};
@@ -0,0 +1,105 @@
+//! Panic information retained across resets.
+//!
+//! The report lives in the `.uninit` section, which the runtime doesn't
+//! zero on startup, so it survives the watchdog reset that follows a panic
+//! (but not a power cycle).
+
+use crate::util::TruncatingWriter;
+use core::{fmt, mem::MaybeUninit, panic::PanicInfo, ptr};
+
+const MAGIC: u32 = 0xC4A5_4E11;
+
+pub const MAX_FILE_LEN: usize = 64;
+pub const MAX_MESSAGE_LEN: usize = 128;
+
+#[derive(Copy, Clone)]
+#[repr(C)]
+pub struct CrashReport {
+    pub line: u32,
+    pub column: u32,
+    file_len: u8,
+    file: [u8; MAX_FILE_LEN],
+    message_len: u8,
+    message: [u8; MAX_MESSAGE_LEN],
+}
+
+impl CrashReport {
+    pub fn file(&self) -> &[u8] {
+        &self.file[..usize::from(self.file_len).min(MAX_FILE_LEN)]
+    }
+
+    pub fn message(&self) -> &[u8] {
+        &self.message[..usize::from(self.message_len).min(MAX_MESSAGE_LEN)]
+    }
+}
+
+impl fmt::Display for CrashReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}:{}:{} {}",
+            core::str::from_utf8(self.file()).unwrap_or("?"),
+            self.line,
+            self.column,
+            core::str::from_utf8(self.message()).unwrap_or("?")
+        )
+    }
+}
+
+#[repr(C)]
+struct Retained {
+    magic: u32,
+    /// Set to `MAGIC` when `report` holds a panic that hasn't been read back
+    report_magic: u32,
+    report: CrashReport,
+}
+
+#[link_section = ".uninit.CRASH_REPORT"]
+static mut RETAINED: MaybeUninit<Retained> = MaybeUninit::uninit();
+
+/// Read back the retained state, must be called once early in init.
+///
+/// Returns the crash report of the previous run, if it panicked.
+pub(crate) fn init() -> Option<CrashReport> {
+    let retained = unsafe { &mut *ptr::addr_of_mut!(RETAINED).cast::<Retained>() };
+    if unsafe { ptr::read_volatile(&retained.magic) } != MAGIC {
+        // Power-on, or garbage from a different firmware
+        retained.magic = MAGIC;
+        retained.report_magic = 0;
+        return None;
+    }
+
+    if unsafe { ptr::read_volatile(&retained.report_magic) } == MAGIC {
+        retained.report_magic = 0;
+        Some(retained.report)
+    } else {
+        None
+    }
+}
+
+/// Record the panic, called from the panic handler with interrupts disabled
+pub(crate) fn record_panic(info: &PanicInfo) {
+    let retained = unsafe { &mut *ptr::addr_of_mut!(RETAINED).cast::<Retained>() };
+    let report = &mut retained.report;
+
+    let (file, line, column) = info
+        .location()
+        .map(|l| (l.file(), l.line(), l.column()))
+        .unwrap_or(("", 0, 0));
+    let file = file.as_bytes();
+    // Keep the end of long paths, it's the interesting part
+    let file = &file[file.len().saturating_sub(MAX_FILE_LEN)..];
+    report.file[..file.len()].copy_from_slice(file);
+    report.file_len = file.len() as u8;
+    report.line = line;
+    report.column = column;
+
+    let mut w = TruncatingWriter::new(&mut report.message);
+    fmt::write(&mut w, format_args!("{}", info.message())).ok();
+    report.message_len = w.len() as u8;
+
+    if unsafe { ptr::read_volatile(&retained.magic) } != MAGIC {
+        retained.magic = MAGIC;
+    }
+    unsafe { ptr::write_volatile(&mut retained.report_magic, MAGIC) };
+}
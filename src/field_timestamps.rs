@@ -0,0 +1,235 @@
+//! Last update times of the broadcast fields that have a validity status flag,
+//! so the data manager can clear the flags of the fields that went stale.
+
+use wire_protocols::StatusFlags;
+
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum Field {
+    Temperature,
+    Humidity,
+    VocTicks,
+    VocIndex,
+}
+
+impl Field {
+    pub const fn name(self) -> &'static str {
+        match self {
+            Field::Temperature => "temperature",
+            Field::Humidity => "humidity",
+            Field::VocTicks => "VOC ticks",
+            Field::VocIndex => "VOC index",
+        }
+    }
+
+    fn clear_flag(self, flags: &mut StatusFlags) {
+        match self {
+            Field::Temperature => flags.set_temperature_valid(false),
+            Field::Humidity => flags.set_humidity_valid(false),
+            Field::VocTicks => flags.set_voc_ticks_valid(false),
+            Field::VocIndex => flags.set_voc_index_valid(false),
+        }
+    }
+}
+
+/// Uptime, in seconds, of the last update to a field
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+struct LastUpdate(Option<u32>);
+
+impl LastUpdate {
+    const fn new() -> Self {
+        LastUpdate(None)
+    }
+
+    /// Returns true, once, when the field is older than `max_age`
+    fn expire(&mut self, now: u32, max_age: u32) -> bool {
+        match self.0 {
+            Some(t) if now.wrapping_sub(t) > max_age => {
+                self.0 = None;
+                true
+            }
+            _ => false,
+        }
+    }
+}
+
+pub struct FieldTimestamps {
+    temperature: LastUpdate,
+    humidity: LastUpdate,
+    voc_ticks: LastUpdate,
+    voc_index: LastUpdate,
+}
+
+impl FieldTimestamps {
+    pub const fn new() -> Self {
+        Self {
+            temperature: LastUpdate::new(),
+            humidity: LastUpdate::new(),
+            voc_ticks: LastUpdate::new(),
+            voc_index: LastUpdate::new(),
+        }
+    }
+
+    /// Record an update to `field` at uptime `now`, in seconds
+    pub fn update(&mut self, field: Field, now: u32) {
+        self.get_mut(field).0 = Some(now);
+    }
+
+    /// Clear the status flags of fields that haven't been updated within `max_age`.
+    /// `on_stale` is called once for each of them, until they're updated again.
+    pub fn invalidate_stale<F: FnMut(Field)>(
+        &mut self,
+        now: u32,
+        max_age: u32,
+        flags: &mut StatusFlags,
+        mut on_stale: F,
+    ) {
+        for field in Self::FIELDS {
+            if self.get_mut(field).expire(now, max_age) {
+                on_stale(field);
+                field.clear_flag(flags);
+            }
+        }
+    }
+
+    /// Clear all of the tracked fields and their status flags
+    pub fn invalidate_all(&mut self, flags: &mut StatusFlags) {
+        *self = Self::new();
+        Self::FIELDS.iter().for_each(|f| f.clear_flag(flags));
+    }
+
+    const FIELDS: [Field; 4] = [
+        Field::Temperature,
+        Field::Humidity,
+        Field::VocTicks,
+        Field::VocIndex,
+    ];
+
+    fn get_mut(&mut self, field: Field) -> &mut LastUpdate {
+        match field {
+            Field::Temperature => &mut self.temperature,
+            Field::Humidity => &mut self.humidity,
+            Field::VocTicks => &mut self.voc_ticks,
+            Field::VocIndex => &mut self.voc_index,
+        }
+    }
+}
+
+impl Default for FieldTimestamps {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MAX_AGE: u32 = 30;
+
+    fn all_valid() -> StatusFlags {
+        let mut flags = StatusFlags::empty();
+        flags.set_temperature_valid(true);
+        flags.set_humidity_valid(true);
+        flags.set_voc_ticks_valid(true);
+        flags.set_voc_index_valid(true);
+        flags
+    }
+
+    /// Temperature, humidity, VOC ticks and VOC index flags
+    fn valid(flags: &StatusFlags) -> [bool; 4] {
+        [
+            flags.temperature_valid(),
+            flags.humidity_valid(),
+            flags.voc_ticks_valid(),
+            flags.voc_index_valid(),
+        ]
+    }
+
+    fn invalidate(ts: &mut FieldTimestamps, now: u32, flags: &mut StatusFlags) -> Vec<Field> {
+        let mut stale = Vec::new();
+        ts.invalidate_stale(now, MAX_AGE, flags, |f| stale.push(f));
+        stale
+    }
+
+    #[test]
+    fn stale_after_max_age_once_then_fresh_again() {
+        let mut ts = FieldTimestamps::new();
+        let mut flags = all_valid();
+        ts.update(Field::Temperature, 100);
+
+        // Fresh within the max age
+        assert!(invalidate(&mut ts, 100, &mut flags).is_empty());
+        assert!(invalidate(&mut ts, 100 + MAX_AGE, &mut flags).is_empty());
+        assert_eq!(valid(&flags), [true; 4]);
+
+        // Stale after it, only the updated field is reported
+        assert_eq!(
+            invalidate(&mut ts, 101 + MAX_AGE, &mut flags),
+            [Field::Temperature]
+        );
+        assert_eq!(valid(&flags), [false, true, true, true]);
+
+        // Reported once
+        flags.set_temperature_valid(true);
+        assert!(invalidate(&mut ts, 200 + MAX_AGE, &mut flags).is_empty());
+        assert!(flags.temperature_valid());
+
+        // Fresh again after the next update
+        ts.update(Field::Temperature, 300);
+        assert!(invalidate(&mut ts, 300 + MAX_AGE, &mut flags).is_empty());
+        assert_eq!(
+            invalidate(&mut ts, 301 + MAX_AGE, &mut flags),
+            [Field::Temperature]
+        );
+    }
+
+    #[test]
+    fn each_field_clears_its_own_flag() {
+        let mut ts = FieldTimestamps::new();
+        let mut flags = all_valid();
+        ts.update(Field::Temperature, 0);
+        ts.update(Field::Humidity, 10);
+        ts.update(Field::VocTicks, 20);
+        ts.update(Field::VocIndex, 30);
+
+        assert_eq!(invalidate(&mut ts, 31, &mut flags), [Field::Temperature]);
+        assert_eq!(valid(&flags), [false, true, true, true]);
+
+        assert_eq!(invalidate(&mut ts, 41, &mut flags), [Field::Humidity]);
+        assert_eq!(valid(&flags), [false, false, true, true]);
+
+        assert_eq!(
+            invalidate(&mut ts, 100, &mut flags),
+            [Field::VocTicks, Field::VocIndex]
+        );
+        assert_eq!(valid(&flags), [false; 4]);
+    }
+
+    #[test]
+    fn never_updated_is_never_stale() {
+        let mut ts = FieldTimestamps::new();
+        let mut flags = all_valid();
+        assert!(invalidate(&mut ts, u32::MAX, &mut flags).is_empty());
+        assert_eq!(valid(&flags), [true; 4]);
+    }
+
+    #[test]
+    fn uptime_wrap() {
+        let mut ts = FieldTimestamps::new();
+        let mut flags = all_valid();
+        ts.update(Field::Humidity, u32::MAX - 5);
+        assert!(invalidate(&mut ts, 10, &mut flags).is_empty());
+        assert_eq!(invalidate(&mut ts, MAX_AGE, &mut flags), [Field::Humidity]);
+    }
+
+    #[test]
+    fn invalidate_all() {
+        let mut ts = FieldTimestamps::new();
+        let mut flags = all_valid();
+        ts.update(Field::Temperature, 0);
+        ts.invalidate_all(&mut flags);
+        assert_eq!(valid(&flags), [false; 4]);
+        // Nothing left to expire
+        assert!(invalidate(&mut ts, 1000, &mut flags).is_empty());
+    }
+}
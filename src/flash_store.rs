@@ -0,0 +1,84 @@
+//! A single CRC-protected record in the last flash sector.
+//!
+//! The sector is excluded from the FLASH region in memory.x. Record layout,
+//! little endian:
+//!
+//! | Offset | Len | Field      |
+//! |--------|-----|------------|
+//! | 0      | 4   | magic      |
+//! | 4      | 2   | length, N  |
+//! | 6      | 2   | reserved   |
+//! | 8      | 4   | CRC-32     |
+//! | 12     | N   | data       |
+
+use crate::settings::Settings;
+use bme680_env_monitor::crc::crc32;
+use byteorder::{ByteOrder, LittleEndian};
+use stm32f4xx_hal::{
+    flash::{Error, FlashExt},
+    pac::FLASH,
+};
+
+const MAGIC: u32 = 0x5EC7_0A7A;
+
+/// Sector 7, the last 128K of the STM32F411CE
+const SECTOR: u8 = 7;
+const SECTOR_OFFSET: usize = 0x6_0000;
+const SECTOR_LEN: usize = 128 * 1024;
+
+const HEADER_LEN: usize = 12;
+
+pub const MAX_DATA_LEN: usize = 1024;
+
+pub struct FlashStore {
+    flash: FLASH,
+}
+
+impl FlashStore {
+    pub fn new(flash: FLASH) -> Self {
+        Self { flash }
+    }
+
+    /// The stored data, `None` if nothing valid was stored
+    pub fn load(&self) -> Option<&[u8]> {
+        let sector = &self.flash.read()[SECTOR_OFFSET..SECTOR_OFFSET + SECTOR_LEN];
+        if LittleEndian::read_u32(&sector[0..4]) != MAGIC {
+            return None;
+        }
+        let len = usize::from(LittleEndian::read_u16(&sector[4..6]));
+        if len > MAX_DATA_LEN {
+            return None;
+        }
+        let data = &sector[HEADER_LEN..HEADER_LEN + len];
+        if LittleEndian::read_u32(&sector[8..12]) != crc32(data) {
+            return None;
+        }
+        Some(data)
+    }
+
+    /// Erase the sector and write `data`, blocks for up to a few seconds
+    pub fn store(&mut self, data: &[u8]) -> Result<(), Error> {
+        assert!(data.len() <= MAX_DATA_LEN);
+        let mut header = [0_u8; HEADER_LEN];
+        LittleEndian::write_u32(&mut header[0..4], MAGIC);
+        LittleEndian::write_u16(&mut header[4..6], data.len() as u16);
+        LittleEndian::write_u32(&mut header[8..12], crc32(data));
+
+        let mut flash = self.flash.unlocked();
+        flash.erase(SECTOR)?;
+        flash.program(SECTOR_OFFSET, header.iter().chain(data.iter()))?;
+        Ok(())
+    }
+
+    /// The stored settings, `None` if nothing valid is stored
+    pub fn load_settings(&self) -> Option<Settings> {
+        self.load().and_then(Settings::parse)
+    }
+
+    /// Erase and write the settings, blocks for up to a few seconds
+    pub fn store_settings(&mut self, settings: &Settings) -> Result<(), Error> {
+        let mut buf = [0_u8; Settings::MAX_ENCODED_LEN];
+        let len = settings.emit(&mut buf);
+        self.store(&buf[..len])
+    }
+}
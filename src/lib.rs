@@ -0,0 +1,24 @@
+//! The hardware independent parts of the firmware, built as a library of
+//! their own so their unit tests run on the host:
+//!
+//! ```bash
+//! cargo test --lib --target x86_64-unknown-linux-gnu
+//! ```
+
+#![deny(warnings, clippy::all)]
+#![cfg_attr(not(test), no_std)]
+
+pub mod calendar;
+pub mod crc;
+pub mod field_timestamps;
+pub mod log_levels;
+pub mod settings;
+
+pub mod net {
+    pub mod sntp;
+}
+
+pub mod sensors {
+    pub mod calibration;
+    pub mod iaq;
+}
@@ -0,0 +1,131 @@
+//! Byte ring buffer between the log producers and the UART.
+//!
+//! Any number of producers, at any priority, and a single consumer. Producers
+//! reserve space with a CAS and copy without disabling interrupts. On a single
+//! core a producer that preempts another completes before it resumes, so the
+//! reserved space is committed, made visible to the consumer, when the
+//! outermost producer is done.
+
+use core::{
+    cell::UnsafeCell,
+    sync::atomic::{AtomicU32, Ordering::*},
+};
+
+pub struct LogBuffer<const N: usize> {
+    buf: UnsafeCell<[u8; N]>,
+    /// End of the reserved bytes
+    reserve: AtomicU32,
+    /// End of the bytes readable by the consumer
+    commit: AtomicU32,
+    /// Start of the unread bytes
+    read: AtomicU32,
+    /// Number of producers in progress
+    writers: AtomicU32,
+    dropped: AtomicU32,
+}
+
+// Producers only write to their reserved space, the consumer only reads committed space
+unsafe impl<const N: usize> Sync for LogBuffer<N> {}
+
+impl<const N: usize> LogBuffer<N> {
+    pub const fn new() -> Self {
+        assert!(N.is_power_of_two() && N <= u32::MAX as usize);
+        Self {
+            buf: UnsafeCell::new([0; N]),
+            reserve: AtomicU32::new(0),
+            commit: AtomicU32::new(0),
+            read: AtomicU32::new(0),
+            writers: AtomicU32::new(0),
+            dropped: AtomicU32::new(0),
+        }
+    }
+
+    /// Total number of bytes dropped because the buffer was full
+    pub fn dropped(&self) -> u32 {
+        self.dropped.load(Relaxed)
+    }
+
+    /// Append all of `bytes`, or none of them if they don't fit.
+    /// Returns false if they were dropped.
+    pub fn write(&self, bytes: &[u8]) -> bool {
+        let len = bytes.len() as u32;
+        self.writers.fetch_add(1, Acquire);
+
+        let start = loop {
+            let head = self.reserve.load(Acquire);
+            let used = head.wrapping_sub(self.read.load(Acquire));
+            if len > N as u32 - used {
+                break None;
+            }
+            if self
+                .reserve
+                .compare_exchange_weak(head, head.wrapping_add(len), AcqRel, Acquire)
+                .is_ok()
+            {
+                break Some(head);
+            }
+        };
+
+        if let Some(start) = start {
+            let buf = self.buf.get() as *mut u8;
+            for (i, b) in bytes.iter().enumerate() {
+                let idx = (start.wrapping_add(i as u32) as usize) & (N - 1);
+                unsafe { buf.add(idx).write_volatile(*b) };
+            }
+        } else {
+            self.dropped.fetch_add(len, Relaxed);
+        }
+
+        if self.writers.fetch_sub(1, AcqRel) == 1 {
+            // A producer may preempt and commit further, the commit only moves forward
+            loop {
+                let commit = self.commit.load(Acquire);
+                let head = self.reserve.load(Acquire);
+                let ahead = head.wrapping_sub(commit);
+                if ahead == 0 || ahead > N as u32 {
+                    break;
+                }
+                if self
+                    .commit
+                    .compare_exchange(commit, head, AcqRel, Acquire)
+                    .is_ok()
+                {
+                    break;
+                }
+            }
+        }
+
+        start.is_some()
+    }
+
+    /// The committed, unread bytes up to the end of the buffer, consumer only
+    pub fn readable(&self) -> &[u8] {
+        let read = self.read.load(Acquire);
+        let commit = self.commit.load(Acquire);
+        let start = read as usize & (N - 1);
+        let len = (commit.wrapping_sub(read) as usize).min(N - start);
+        let buf = self.buf.get() as *const u8;
+        unsafe { core::slice::from_raw_parts(buf.add(start), len) }
+    }
+
+    /// Release `n` bytes returned by `readable`, consumer only
+    pub fn consume(&self, n: usize) {
+        self.read.fetch_add(n as u32, Release);
+    }
+
+    /// Copy and release up to `out.len()` committed bytes, consumer only
+    pub fn read(&self, out: &mut [u8]) -> usize {
+        let mut len = 0;
+        while len < out.len() {
+            let bytes = self.readable();
+            if bytes.is_empty() {
+                break;
+            }
+            let n = bytes.len().min(out.len() - len);
+            out[len..len + n].copy_from_slice(&bytes[..n]);
+            self.consume(n);
+            len += n;
+        }
+        len
+    }
+}
@@ -0,0 +1,61 @@
+//! The runtime log levels, applied by the firmware's logger and stored with
+//! the settings.
+
+use core::fmt;
+use log::LevelFilter;
+
+/// Modules whose level can be set on their own, relative to the crate root.
+/// Submodules are included, e.g. `net` covers `net::eth`, the longest match wins.
+/// The settings store levels by index, only append to this.
+pub const MODULES: [&str; 15] = [
+    "app",
+    "command",
+    "net",
+    "net::eth",
+    "net::ip_config",
+    "tasks::bme680",
+    "tasks::command",
+    "tasks::data_manager",
+    "tasks::sntp",
+    "tasks::watchdog",
+    "tasks::shell",
+    "tasks::syslog",
+    "tasks::serial",
+    "net::syslog",
+    "net::stats",
+];
+
+/// The maximum log level, and per-module overrides indexed like `MODULES`
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub struct LogLevels {
+    pub default: LevelFilter,
+    pub modules: [Option<LevelFilter>; MODULES.len()],
+    /// Minimum level sent to the syslog collector, independent of the others
+    pub syslog: LevelFilter,
+}
+
+impl LogLevels {
+    /// Index into `MODULES`
+    pub fn module_index(module: &str) -> Option<usize> {
+        MODULES.iter().position(|m| *m == module)
+    }
+}
+
+impl fmt::Display for LogLevels {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "default: {}", self.default)?;
+        for (m, l) in MODULES.iter().zip(self.modules.iter()) {
+            if let Some(l) = l {
+                write!(f, ", {m}: {l}")?;
+            }
+        }
+        write!(f, ", syslog: {}", self.syslog)
+    }
+}
+
+pub fn level_filter_from_u8(value: u8) -> Option<LevelFilter> {
+    use LevelFilter::*;
+    [Off, Error, Warn, Info, Debug, Trace]
+        .get(usize::from(value))
+        .copied()
+}
@@ -1,63 +1,310 @@
+use crate::{config, log_buffer::LogBuffer, net::syslog, time_service, util::TruncatingWriter};
 use core::fmt::{self, Write as FmtWrite};
-use core::{cell::RefCell, mem::MaybeUninit};
-use cortex_m::interrupt::{self, Mutex};
-use log::{Metadata, Record};
+use core::sync::atomic::{
+    AtomicBool, AtomicU32, AtomicU8,
+    Ordering::{self, Relaxed},
+};
+use log::{LevelFilter, Metadata, Record};
 use stm32f4xx_hal::{
-    hal::blocking::serial::Write,
-    pac::USART6,
-    serial::{Instance, Tx},
+    pac::{self, DMA2, RCC, USART6},
+    serial::Tx,
 };
 
-type Inner<T> = Mutex<RefCell<Tx<T>>>;
-pub struct Logger<T: Instance>(Inner<T>);
-pub type LoggerUsart6 = MaybeUninit<Logger<USART6>>;
+pub use bme680_env_monitor::log_levels::{level_filter_from_u8, LogLevels, MODULES};
+
+/// Formats the records into `BUFFER`, DMA2 stream 6 sends them, see `drain`
+pub struct Logger;
+
+static LOGGER: Logger = Logger;
+
+static BUFFER: LogBuffer<{ config::LOG_BUFFER_LEN }> = LogBuffer::new();
+
+/// Number of dropped bytes already reported in the log
+static DROPPED_REPORTED: AtomicU32 = AtomicU32::new(0);
+
+/// Set once the DMA is set up, until then the output is sent synchronously
+static DMA_DRAIN: AtomicBool = AtomicBool::new(false);
+
+/// Length of the DMA transfer in progress, consumed from `BUFFER` once it's done
+static IN_FLIGHT: AtomicU32 = AtomicU32::new(0);
+
+/// USART6_TX is DMA2 stream 6 channel 5
+const DMA_STREAM: usize = 6;
+const DMA_CHANNEL: u32 = 5;
 
-static mut LOGGER: LoggerUsart6 = LoggerUsart6::uninit();
+/// DMA_SxCR bits
+const DMA_CR_EN: u32 = 1 << 0;
+const DMA_CR_TEIE: u32 = 1 << 2;
+const DMA_CR_TCIE: u32 = 1 << 4;
+const DMA_CR_DIR_M2P: u32 = 0b01 << 6;
+const DMA_CR_MINC: u32 = 1 << 10;
+const DMA_CR_CHSEL_SHIFT: u32 = 25;
 
-pub(crate) unsafe fn init_logging(tx: Tx<USART6>) {
-    LOGGER.write(Logger(Mutex::new(RefCell::new(tx))));
-    log::set_logger(&*LOGGER.as_ptr())
-        .map(|()| log::set_max_level(log::LevelFilter::Trace))
+/// Stream 6 flags in DMA_HIFCR, FEIF, DMEIF, TEIF, HTIF and TCIF
+const DMA_HIFCR_STREAM6: u32 = 0b11_1101 << 16;
+
+/// What precedes the message of a record, after the level marker
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub struct LogFormat {
+    /// Seconds since boot, with milliseconds
+    pub uptime: bool,
+    /// UTC time, once it's set by the RTC or SNTP
+    pub utc: bool,
+    /// Module path and line
+    pub location: bool,
+}
+
+/// Make `levels` the active levels. Without a syslog collector nothing is
+/// queued for it, regardless of the syslog level.
+pub fn apply_levels(levels: &LogLevels, syslog_collector: bool) {
+    let syslog = if syslog_collector {
+        levels.syslog
+    } else {
+        LevelFilter::Off
+    };
+    DEFAULT_LEVEL.store(levels.default as u8, Relaxed);
+    SYSLOG_LEVEL.store(syslog as u8, Relaxed);
+    let mut max = levels.default.max(syslog);
+    for (level, l) in MODULE_LEVELS.iter().zip(levels.modules.iter()) {
+        level.store(l.map(|l| l as u8).unwrap_or(NO_OVERRIDE), Relaxed);
+        max = max.max(l.unwrap_or(LevelFilter::Off));
+    }
+    // The log macros check this before calling into the logger
+    log::set_max_level(max);
+}
+
+const NO_OVERRIDE: u8 = 0xFF;
+
+static DEFAULT_LEVEL: AtomicU8 = AtomicU8::new(LevelFilter::Trace as u8);
+static SYSLOG_LEVEL: AtomicU8 = AtomicU8::new(LevelFilter::Off as u8);
+static MODULE_LEVELS: [AtomicU8; MODULES.len()] = {
+    #[allow(clippy::declare_interior_mutable_const)]
+    const NONE: AtomicU8 = AtomicU8::new(NO_OVERRIDE);
+    [NONE; MODULES.len()]
+};
+
+/// A module path relative to the crate root
+pub(crate) fn relative_module(path: &str) -> &str {
+    path.strip_prefix(concat!(env!("CARGO_CRATE_NAME"), "::"))
+        .unwrap_or(path)
+}
+
+/// The active level of a log target, the module path by default
+fn target_level(target: &str) -> LevelFilter {
+    let module = relative_module(target);
+    let mut best: Option<(usize, u8)> = None;
+    for (idx, m) in MODULES.iter().enumerate() {
+        let level = MODULE_LEVELS[idx].load(Relaxed);
+        let matches = module
+            .strip_prefix(m)
+            .map(|rest| rest.is_empty() || rest.starts_with("::"))
+            .unwrap_or(false);
+        if level != NO_OVERRIDE && matches && best.map(|(len, _)| m.len() > len).unwrap_or(true) {
+            best = Some((m.len(), level));
+        }
+    }
+    let level = best
+        .map(|(_, level)| level)
+        .unwrap_or_else(|| DEFAULT_LEVEL.load(Relaxed));
+    level_filter_from_u8(level).unwrap_or(LevelFilter::Trace)
+}
+
+pub(crate) fn init_logging() {
+    log::set_logger(&LOGGER)
+        .map(|()| log::set_max_level(LevelFilter::Trace))
         .unwrap();
 }
 
-pub(crate) unsafe fn get_logger() -> &'static mut dyn fmt::Write {
-    &mut *LOGGER.as_mut_ptr()
+/// Hand the output over to the DMA, called at the end of init.
+/// The Tx half and DMA2 are used through their registers from here on.
+pub(crate) fn enable_dma_drain(_tx: Tx<USART6>, _dma: DMA2) {
+    let rcc = unsafe { &*RCC::ptr() };
+    let usart = unsafe { &*USART6::ptr() };
+    let dma = unsafe { &*DMA2::ptr() };
+    rcc.ahb1enr.modify(|_, w| w.dma2en().set_bit());
+    let stream = &dma.st[DMA_STREAM];
+    stream
+        .par
+        .write(|w| unsafe { w.bits(&usart.dr as *const _ as u32) });
+    stream.cr.write(|w| unsafe {
+        w.bits(
+            (DMA_CHANNEL << DMA_CR_CHSEL_SHIFT)
+                | DMA_CR_MINC
+                | DMA_CR_DIR_M2P
+                | DMA_CR_TCIE
+                | DMA_CR_TEIE,
+        )
+    });
+    usart.cr3.modify(|_, w| w.dmat().set_bit());
+    DMA_DRAIN.store(true, Ordering::Release);
+    rtic::pend(pac::Interrupt::DMA2_STREAM6);
+}
+
+/// Total number of bytes dropped because the buffer was full
+pub(crate) fn dropped() -> u32 {
+    BUFFER.dropped()
+}
+
+/// Write a block of text, log records can't interleave with it
+pub(crate) fn write_str(s: &str) {
+    BUFFER.write(s.as_bytes());
+    kick();
+}
+
+/// Release the bytes the last DMA transfer sent and start the next one,
+/// unless one is still in progress. DMA2 stream 6 interrupt only.
+pub(crate) fn drain() {
+    let dma = unsafe { &*DMA2::ptr() };
+    let stream = &dma.st[DMA_STREAM];
+    if stream.cr.read().bits() & DMA_CR_EN != 0 {
+        return;
+    }
+    dma.hifcr.write(|w| unsafe { w.bits(DMA_HIFCR_STREAM6) });
+    BUFFER.consume(IN_FLIGHT.swap(0, Relaxed) as usize);
+
+    // Up to the end of the ring, the rest goes in the next transfer
+    let bytes = BUFFER.readable();
+    if bytes.is_empty() {
+        return;
+    }
+    IN_FLIGHT.store(bytes.len() as u32, Relaxed);
+    stream
+        .m0ar
+        .write(|w| unsafe { w.bits(bytes.as_ptr() as u32) });
+    stream.ndtr.write(|w| unsafe { w.bits(bytes.len() as u32) });
+    stream
+        .cr
+        .modify(|r, w| unsafe { w.bits(r.bits() | DMA_CR_EN) });
+}
+
+/// Send the buffered output, busy waiting on the UART. For the panic handler,
+/// with interrupts disabled.
+pub(crate) fn flush_blocking() {
+    if DMA_DRAIN.load(Ordering::Acquire) {
+        // Stop the DMA, keeping what it already sent
+        let dma = unsafe { &*DMA2::ptr() };
+        let stream = &dma.st[DMA_STREAM];
+        stream
+            .cr
+            .modify(|r, w| unsafe { w.bits(r.bits() & !DMA_CR_EN) });
+        while stream.cr.read().bits() & DMA_CR_EN != 0 {}
+        let remaining = stream.ndtr.read().bits();
+        BUFFER.consume(IN_FLIGHT.swap(0, Relaxed).saturating_sub(remaining) as usize);
+    }
+
+    loop {
+        let bytes = BUFFER.readable();
+        if bytes.is_empty() {
+            break;
+        }
+        bytes.iter().for_each(|b| write_byte_blocking(*b));
+        BUFFER.consume(bytes.len());
+    }
+}
+
+/// Writes directly to the UART, busy waiting. For the panic handler, after `flush_blocking`.
+pub(crate) struct BlockingWriter;
+
+impl fmt::Write for BlockingWriter {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        s.bytes().for_each(write_byte_blocking);
+        Ok(())
+    }
+}
+
+fn write_byte_blocking(byte: u8) {
+    // The Tx half was handed to `enable_dma_drain`, the registers are used directly
+    let usart = unsafe { &*USART6::ptr() };
+    while usart.sr.read().txe().bit_is_clear() {}
+    usart.dr.write(|w| unsafe { w.dr().bits(u16::from(byte)) });
 }
 
-impl log::Log for Logger<USART6> {
+/// Get the buffered output sent
+fn kick() {
+    if DMA_DRAIN.load(Ordering::Acquire) {
+        rtic::pend(pac::Interrupt::DMA2_STREAM6);
+    } else {
+        flush_blocking();
+    }
+}
+
+impl log::Log for Logger {
     fn enabled(&self, metadata: &Metadata) -> bool {
-        metadata.level() <= log::max_level()
+        metadata.level() <= log::max_level() && (uart_enabled(metadata) || syslog_enabled(metadata))
     }
 
     fn log(&self, record: &Record) {
-        if self.enabled(record.metadata()) {
-            interrupt::free(|cs| {
-                writeln!(
-                    self.0.borrow(cs).borrow_mut(),
-                    "{} {}\r",
-                    level_marker(record.level()),
-                    record.args()
-                )
-                .ok();
-            });
+        if syslog_enabled(record.metadata()) {
+            syslog::push(record);
+        }
+        if !uart_enabled(record.metadata()) {
+            return;
+        }
+
+        let dropped = BUFFER.dropped();
+        let reported = DROPPED_REPORTED.load(Relaxed);
+        if dropped != reported {
+            let mut buf = [0_u8; 64];
+            let mut w = TruncatingWriter::new(&mut buf);
+            write!(
+                w,
+                "[W] log: {} bytes dropped\r\n",
+                dropped.wrapping_sub(reported)
+            )
+            .ok();
+            let len = w.len();
+            if BUFFER.write(&buf[..len]) {
+                DROPPED_REPORTED
+                    .compare_exchange(reported, dropped, Relaxed, Relaxed)
+                    .ok();
+            }
         }
+
+        // Records longer than this are truncated
+        let mut buf = [0_u8; config::LOG_RECORD_MAX_LEN];
+        let end = buf.len() - 2;
+        let mut w = TruncatingWriter::new(&mut buf[..end]);
+        write_prefix(&mut w, record).ok();
+        write!(w, "{}", record.args()).ok();
+        let len = w.len();
+        buf[len..len + 2].copy_from_slice(b"\r\n");
+        BUFFER.write(&buf[..len + 2]);
+        kick();
     }
 
     fn flush(&self) {
-        interrupt::free(|cs| {
-            self.0.borrow(cs).borrow_mut().bflush().ok();
-        });
+        kick();
     }
 }
 
-impl fmt::Write for Logger<USART6> {
-    fn write_str(&mut self, s: &str) -> fmt::Result {
-        interrupt::free(|cs| {
-            self.0.borrow(cs).borrow_mut().bwrite_all(s.as_bytes()).ok();
-        });
-        Ok(())
+fn uart_enabled(metadata: &Metadata) -> bool {
+    metadata.level() <= target_level(metadata.target())
+}
+
+fn syslog_enabled(metadata: &Metadata) -> bool {
+    level_filter_from_u8(SYSLOG_LEVEL.load(Relaxed))
+        .map(|l| metadata.level() <= l)
+        .unwrap_or(false)
+}
+
+/// E.g. `[I] 12.345 2023-06-01T12:00:00Z net::eth:42 `, see `config::LOG_FORMAT`
+fn write_prefix<W: FmtWrite>(w: &mut W, record: &Record) -> fmt::Result {
+    let format = config::LOG_FORMAT;
+    write!(w, "{} ", level_marker(record.level()))?;
+    if format.uptime {
+        let ms = time_service::uptime_us() / 1_000;
+        write!(w, "{}.{:03} ", ms / 1_000, ms % 1_000)?;
+    }
+    if format.utc {
+        if let Some(utc) = time_service::utc_now() {
+            write!(w, "{utc} ")?;
+        }
+    }
+    if format.location {
+        let module = record.module_path().map(relative_module).unwrap_or("?");
+        write!(w, "{module}:{} ", record.line().unwrap_or(0))?;
     }
+    Ok(())
 }
 
 const fn level_marker(level: log::Level) -> &'static str {
@@ -0,0 +1,74 @@
+//! Log macros for the tasks and the network code.
+//!
+//! They forward to the `log` crate, the USART6 logger, or with the `defmt`
+//! feature to defmt over RTT, so the format strings must work with both:
+//! positional `{}` and `{:?}` only, primitive arguments, anything else wrapped
+//! in `Display2Format` or `Debug2Format`.
+
+#[cfg(feature = "defmt")]
+pub(crate) use defmt::{Debug2Format, Display2Format};
+
+#[cfg(not(feature = "defmt"))]
+mod fmt_shims {
+    use core::fmt;
+
+    /// Formats the value with `Debug`, for `{:?}`
+    pub(crate) struct Debug2Format<'a, T: fmt::Debug + ?Sized>(pub &'a T);
+
+    impl<'a, T: fmt::Debug + ?Sized> fmt::Debug for Debug2Format<'a, T> {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            self.0.fmt(f)
+        }
+    }
+
+    /// Formats the value with `Display`, for `{}`
+    pub(crate) struct Display2Format<'a, T: fmt::Display + ?Sized>(pub &'a T);
+
+    impl<'a, T: fmt::Display + ?Sized> fmt::Display for Display2Format<'a, T> {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            self.0.fmt(f)
+        }
+    }
+}
+
+#[cfg(not(feature = "defmt"))]
+pub(crate) use fmt_shims::{Debug2Format, Display2Format};
+
+macro_rules! log_error {
+    ($($arg:tt)+) => {{
+        #[cfg(not(feature = "defmt"))]
+        ::log::error!($($arg)+);
+        #[cfg(feature = "defmt")]
+        ::defmt::error!($($arg)+);
+    }};
+}
+
+macro_rules! log_warn {
+    ($($arg:tt)+) => {{
+        #[cfg(not(feature = "defmt"))]
+        ::log::warn!($($arg)+);
+        #[cfg(feature = "defmt")]
+        ::defmt::warn!($($arg)+);
+    }};
+}
+
+macro_rules! log_info {
+    ($($arg:tt)+) => {{
+        #[cfg(not(feature = "defmt"))]
+        ::log::info!($($arg)+);
+        #[cfg(feature = "defmt")]
+        ::defmt::info!($($arg)+);
+    }};
+}
+
+macro_rules! log_debug {
+    ($($arg:tt)+) => {{
+        #[cfg(not(feature = "defmt"))]
+        ::log::debug!($($arg)+);
+        #[cfg(feature = "defmt")]
+        ::defmt::debug!($($arg)+);
+    }};
+}
+
+// Renamed on export, a `warn` macro_rules is ambiguous with the built-in attribute
+pub(crate) use {log_debug as debug, log_error as error, log_info as info, log_warn as warn};
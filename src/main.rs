@@ -2,39 +2,83 @@
 #![no_main]
 #![no_std]
 
+mod command;
 mod config;
+mod crash_report;
+mod flash_store;
+mod log_buffer;
 mod logger;
+mod logging;
 mod net;
 mod panic_handler;
+mod reset_cause;
+mod rtc;
 mod sensors;
 mod tasks;
+mod time_service;
 mod util;
 
+use bme680_env_monitor::{field_timestamps, settings};
+
+#[cfg(feature = "defmt")]
+use defmt_rtt as _;
+
+#[cfg(feature = "defmt")]
+defmt::timestamp!("{=u64:us}", crate::time_service::uptime_us());
+
 pub mod built_info {
     include!(concat!(env!("OUT_DIR"), "/built.rs"));
 }
 
 #[rtic::app(device = stm32f4xx_hal::pac, dispatchers = [EXTI0, EXTI1, EXTI2])]
 mod app {
-    use crate::net::{Eth, EthernetStorage, NetworkStorage, UdpSocketStorage};
-    use crate::sensors::Bme680;
+    use crate::net::{
+        ip_config::Mode as IpConfigMode, sntp, syslog, Eth, EthernetStorage, IpConfig,
+        NetworkStorage, UdpSocketStorage,
+    };
+    use crate::sensors::{bme680::Measurement, Bme680, I2cBus, I2cBusCell, I2cProxy};
     use crate::tasks::{
+        bme680::{SpawnArg as Bme680SpawnArg, TaskState as Bme680TaskState},
         bme680_task,
+        command::MAX_RESPONSE_LEN as COMMAND_MAX_RESPONSE_LEN,
+        command_task,
         data_manager::{SpawnArg as DataManagerSpawnArg, TaskState as DataManagerTaskState},
         data_manager_task, eth_gpio_interrupt_handler_task, ipstack_clock_timer_task,
-        ipstack_poll_task, ipstack_poll_timer_task, watchdog_task,
+        ipstack_poll_task, ipstack_poll_timer_task, log_dma_task, serial_task,
+        shell::{Line as ShellLine, RxState as ShellRxState},
+        shell_task,
+        sntp::{SpawnArg as SntpSpawnArg, TaskState as SntpTaskState},
+        sntp_task,
+        syslog::TaskState as SyslogTaskState,
+        syslog_task,
+        watchdog::TaskState as WatchdogTaskState,
+        watchdog_task,
+    };
+    use crate::{
+        config,
+        crash_report::CrashReport,
+        flash_store::FlashStore,
+        reset_cause::{ResetCause, ResetReport},
+        rtc::Rtc,
+        settings::Settings,
+        time_service::TimeService,
+        util,
     };
-    use crate::{config, util};
-    use log::{debug, info};
+    use core::cell::RefCell;
+    use log::{debug, info, warn};
     use smoltcp::{
         iface::{Config, Interface, SocketHandle, SocketSet},
-        socket::udp::{PacketBuffer as UdpPacketBuffer, Socket as UdpSocket},
-        wire::{EthernetAddress, Ipv4Address},
+        socket::{
+            dhcpv4::Socket as DhcpSocket,
+            udp::{PacketBuffer as UdpPacketBuffer, Socket as UdpSocket},
+        },
+        wire::EthernetAddress,
     };
     use stm32f4xx_hal::{
         gpio::{Edge, Output, PushPull, Speed as GpioSpeed, PC13},
-        pac::{self, TIM10, TIM3},
+        pac::{self, TIM10, TIM3, USART6},
         prelude::*,
+        serial::{Event as SerialEvent, Rx},
         spi::Spi,
         timer::counter::CounterHz,
         timer::{DelayMs, Event, MonoTimerUs, SysCounterUs, SysEvent},
@@ -53,6 +97,24 @@ mod app {
         sockets: SocketSet<'static>,
         #[lock_free]
         udp_socket: SocketHandle,
+        #[lock_free]
+        sntp_socket: SocketHandle,
+        #[lock_free]
+        command_socket: SocketHandle,
+        #[lock_free]
+        syslog_socket: SocketHandle,
+        #[lock_free]
+        dhcp_socket: Option<SocketHandle>,
+        #[lock_free]
+        ip_config: IpConfig,
+        #[lock_free]
+        time: TimeService,
+        #[lock_free]
+        settings: Settings,
+        #[lock_free]
+        flash_store: FlashStore,
+        #[lock_free]
+        last_measurement: Option<Measurement>,
     }
 
     #[local]
@@ -61,7 +123,11 @@ mod app {
         ipstack_poll_timer: CounterHz<TIM3>,
         led: LedPin,
         watchdog: IndependentWatchdog,
-        bme680: Bme680<DelayMs<TIM10>>,
+        bme680: Bme680<DelayMs<TIM10>, I2cProxy>,
+        i2c_bus: I2cBus,
+        shell_rx: Rx<USART6>,
+        crash_report: Option<CrashReport>,
+        reset_report: Option<ResetReport>,
     }
 
     /// TIM2 is a 32-bit timer, defaults to having the highest interrupt priority
@@ -70,14 +136,24 @@ mod app {
 
     #[init(local = [
         eth_storage: EthernetStorage<{Eth::MTU}> = EthernetStorage::new(),
-        net_storage: NetworkStorage<1> = NetworkStorage::new(),
+        net_storage: NetworkStorage<5> = NetworkStorage::new(),
         udp_socket_storage: UdpSocketStorage<{config::SOCKET_BUFFER_LEN}> = UdpSocketStorage::new(),
+        sntp_socket_storage: UdpSocketStorage<{sntp::PACKET_LEN}> = UdpSocketStorage::new(),
+        command_socket_storage: UdpSocketStorage<COMMAND_MAX_RESPONSE_LEN> = UdpSocketStorage::new(),
+        syslog_socket_storage: UdpSocketStorage<{syslog::MAX_MESSAGE_LEN}> = UdpSocketStorage::new(),
+        i2c_bus_cell: I2cBusCell = RefCell::new(None),
     ])]
     fn init(mut ctx: init::Context) -> (Shared, Local, init::Monotonics) {
+        let reset_cause = ResetCause::read_and_clear();
+        let crash_report = crate::crash_report::init();
+
         let mut syscfg = ctx.device.SYSCFG.constrain();
         let rcc = ctx.device.RCC.constrain();
         let clocks = rcc.cfgr.use_hse(25.MHz()).sysclk(64.MHz()).freeze();
 
+        // Started first, the log timestamps and uptime include init
+        let mono = ctx.device.TIM2.monotonic_us(&clocks);
+
         let mut watchdog = IndependentWatchdog::new(ctx.device.IWDG);
         watchdog.start(config::WATCHDOG_RESET_PERIOD_MS.millis());
         watchdog.feed();
@@ -89,18 +165,26 @@ mod app {
         // Turn it off, active-low
         let led: LedPin = gpioc.pc13.into_push_pull_output_in_state(true.into());
 
-        // Setup logging impl via USART6, Rx on PA12, Tx on PA11
+        // Setup logging impl and the shell via USART6, Rx on PA12, Tx on PA11
         // This is also the virtual com port on the nucleo boards: stty -F /dev/ttyACM0 115200
         let log_tx_pin = gpioa.pa11.into_alternate();
-        let log_tx = ctx
+        let shell_rx_pin = gpioa.pa12.into_alternate();
+        let mut serial = ctx
             .device
             .USART6
-            .tx(log_tx_pin, 115_200.bps(), &clocks)
+            .serial((log_tx_pin, shell_rx_pin), 115_200.bps(), &clocks)
             .unwrap();
-        unsafe { crate::logger::init_logging(log_tx) };
+        serial.listen(SerialEvent::Rxne);
+        let (log_tx, shell_rx) = serial.split();
+        crate::logger::init_logging();
 
         debug!("Watchdog: inerval {}", watchdog.interval());
 
+        let flash_store = FlashStore::new(ctx.device.FLASH);
+        let stored_settings = flash_store.load_settings();
+        let settings = stored_settings.unwrap_or(config::DEFAULT_SETTINGS);
+        crate::logger::apply_levels(&settings.log_levels, settings.syslog_collector());
+
         info!("############################################################");
         info!(
             "{} {} ({})",
@@ -114,21 +198,40 @@ mod app {
             info!("git commit: {}", gc);
         }
         info!("Serial number: {:X}", util::read_device_serial_number());
+        info!("Reset cause: {reset_cause}");
+        if let Some(report) = &crash_report {
+            warn!("Previous run panicked: {report}");
+        }
+        info!(
+            "Settings: {}",
+            if stored_settings.is_some() {
+                "flash"
+            } else {
+                "compiled defaults"
+            }
+        );
         info!(
             "Device ID: 0x{:X} ({})",
-            config::DEVICE_ID,
-            config::DEVICE_ID
+            settings.device_id, settings.device_id
         );
-        info!("IP address: {}", config::IP_CIDR.address());
+        info!("IP config mode: {:?}", settings.ip_config_mode);
+        info!("Static IP address: {}", settings.static_ip_cidr.address());
+        info!("Static gateway: {}", settings.static_gateway);
         info!(
             "MAC address: {}",
-            EthernetAddress::from_bytes(&config::MAC_ADDRESS)
+            EthernetAddress::from_bytes(&settings.mac_address)
         );
-        info!("Broadcast protocol port: {}", config::BROADCAST_PORT);
+        info!("Broadcast protocol port: {}", settings.broadcast_port);
+        info!("Diagnostics port: {}", settings.diagnostics_port());
+        info!("Broadcast protocol address: {}", settings.broadcast_address);
+        info!("SNTP server: {}", settings.sntp_server_address);
+        info!("Command port: {}", settings.command_port());
         info!(
-            "Broadcast protocol address: {}",
-            Ipv4Address(config::BROADCAST_ADDRESS)
+            "Syslog collector: {}:{}",
+            settings.syslog_address, settings.syslog_port
         );
+        info!("Calibration: {}", settings.calibration);
+        info!("Log levels: {}", settings.log_levels);
         info!("############################################################");
 
         let mut common_delay = ctx.device.TIM4.delay_ms(&clocks);
@@ -145,12 +248,32 @@ mod app {
         }
         watchdog.feed();
 
+        info!("Setup: RTC");
+        let mut rtc = Rtc::new(ctx.device.RTC, ctx.device.PWR, &clocks);
+        let reset_report = ResetReport::new(reset_cause, &mut rtc);
+        info!(
+            "Reset cause count: {}",
+            reset_report.counters[reset_cause as usize]
+        );
+        let time = TimeService::new(rtc);
+        info!("RTC clock source: {:?}", time.clock_source());
+        match time.utc() {
+            Some(utc) => info!("RTC time: {utc}"),
+            None => info!("RTC time: not set"),
+        }
+        watchdog.feed();
+
         info!("Setup: BME680");
         let bme680_delay = ctx.device.TIM10.delay_ms(&clocks);
         let scl = gpiob.pb10.into_alternate().set_open_drain();
         let sda = gpiob.pb3.into_alternate().set_open_drain();
-        let i2c2 = ctx.device.I2C2.i2c((scl, sda), 100.kHz(), &clocks);
-        let bme680 = Bme680::new(i2c2, bme680_delay).unwrap();
+        let i2c2 = ctx
+            .device
+            .I2C2
+            .i2c((scl, sda), config::BME680_I2C_FREQ_KHZ.kHz(), &clocks);
+        let i2c_bus = I2cBus::new(ctx.local.i2c_bus_cell, i2c2, clocks);
+        let bme680 =
+            Bme680::new(i2c_bus.proxy(), bme680_delay, config::BME680_HEATER_PROFILE).unwrap();
 
         info!("Setup: ETH");
         let eth_spi = {
@@ -179,44 +302,27 @@ mod app {
             int.enable_interrupt(&mut ctx.device.EXTI);
             int.trigger_on_edge(&mut ctx.device.EXTI, Edge::Falling);
 
-            let mut reset = gpiob.pb1.into_push_pull_output_in_state(true.into());
-
-            // Perform a hard reset first, then let the driver
-            // perform a soft reset by provided enc28j60::Unconnected
-            // instead of the actual reset pin
-            reset.set_low();
-            common_delay.delay_ms(5_u8);
-            reset.set_high();
-            common_delay.delay_ms(5_u8);
+            let reset = gpiob.pb1.into_push_pull_output_in_state(true.into());
 
-            let mut enc = enc28j60::Enc28j60::new(
+            // Resets and initializes the controller, again when it gets stuck
+            Eth::new(
                 eth_spi,
                 ncs,
                 int,
-                enc28j60::Unconnected,
-                &mut common_delay,
-                7168,
-                config::MAC_ADDRESS,
-            )
-            .unwrap();
-
-            enc.listen(enc28j60::Event::Pkt).unwrap();
-
-            Eth::new(
-                enc,
+                reset,
+                common_delay,
+                settings.mac_address,
                 &mut ctx.local.eth_storage.rx_buffer[..],
                 &mut ctx.local.eth_storage.tx_buffer[..],
             )
         };
 
         info!("Setup: TCP/IP");
-        let mac = EthernetAddress::from_bytes(&config::MAC_ADDRESS);
+        let mac = EthernetAddress::from_bytes(&settings.mac_address);
         let mut config = Config::new();
         config.hardware_addr = Some(mac.into());
         let mut eth_iface = Interface::new(config, &mut eth);
-        eth_iface.update_ip_addrs(|addr| {
-            addr.push(config::IP_CIDR.into()).unwrap();
-        });
+        let mut ip_config = IpConfig::new(&settings);
         let mut sockets = SocketSet::new(&mut ctx.local.net_storage.sockets[..]);
         let udp_rx_buf = UdpPacketBuffer::new(
             &mut ctx.local.udp_socket_storage.rx_metadata[..],
@@ -228,6 +334,43 @@ mod app {
         );
         let udp_socket = UdpSocket::new(udp_rx_buf, udp_tx_buf);
         let udp_handle = sockets.add(udp_socket);
+        let sntp_rx_buf = UdpPacketBuffer::new(
+            &mut ctx.local.sntp_socket_storage.rx_metadata[..],
+            &mut ctx.local.sntp_socket_storage.rx_buffer[..],
+        );
+        let sntp_tx_buf = UdpPacketBuffer::new(
+            &mut ctx.local.sntp_socket_storage.tx_metadata[..],
+            &mut ctx.local.sntp_socket_storage.tx_buffer[..],
+        );
+        let sntp_socket = UdpSocket::new(sntp_rx_buf, sntp_tx_buf);
+        let sntp_handle = sockets.add(sntp_socket);
+        let command_rx_buf = UdpPacketBuffer::new(
+            &mut ctx.local.command_socket_storage.rx_metadata[..],
+            &mut ctx.local.command_socket_storage.rx_buffer[..],
+        );
+        let command_tx_buf = UdpPacketBuffer::new(
+            &mut ctx.local.command_socket_storage.tx_metadata[..],
+            &mut ctx.local.command_socket_storage.tx_buffer[..],
+        );
+        let command_socket = UdpSocket::new(command_rx_buf, command_tx_buf);
+        let command_handle = sockets.add(command_socket);
+        let syslog_rx_buf = UdpPacketBuffer::new(
+            &mut ctx.local.syslog_socket_storage.rx_metadata[..],
+            &mut ctx.local.syslog_socket_storage.rx_buffer[..],
+        );
+        let syslog_tx_buf = UdpPacketBuffer::new(
+            &mut ctx.local.syslog_socket_storage.tx_metadata[..],
+            &mut ctx.local.syslog_socket_storage.tx_buffer[..],
+        );
+        let syslog_socket = UdpSocket::new(syslog_rx_buf, syslog_tx_buf);
+        let syslog_handle = sockets.add(syslog_socket);
+        let dhcp_handle = match ip_config.mode() {
+            IpConfigMode::Static => {
+                ip_config.apply_static(&mut eth_iface);
+                None
+            }
+            IpConfigMode::Dhcp => Some(sockets.add(DhcpSocket::new())),
+        };
 
         info!("Setup: net clock timer");
         let mut net_clock_timer = ctx.core.SYST.counter_us(&clocks);
@@ -239,18 +382,26 @@ mod app {
         ipstack_poll_timer.start(25.Hz()).unwrap();
         ipstack_poll_timer.listen(Event::Update);
 
-        let mono = ctx.device.TIM2.monotonic_us(&clocks);
         info!(">>> Initialized <<<");
         watchdog.feed();
+        crate::logger::enable_dma_drain(log_tx, ctx.device.DMA2);
 
         watchdog_task::spawn().unwrap();
-        bme680_task::spawn().unwrap();
+        bme680_task::spawn(Bme680SpawnArg::Measure).unwrap();
+        sntp_task::spawn(SntpSpawnArg::SendRequest).unwrap();
+        command_task::spawn().unwrap();
+        syslog_task::spawn().unwrap();
 
         data_manager_task::spawn_after(
-            config::BCAST_INTERVAL_SEC.secs(),
+            settings.bcast_interval_sec.secs(),
             DataManagerSpawnArg::SendBroadcastMessage,
         )
         .unwrap();
+        data_manager_task::spawn_after(
+            config::NET_STATS_INTERVAL_SEC.secs(),
+            DataManagerSpawnArg::SendNetStatistics,
+        )
+        .unwrap();
 
         (
             Shared {
@@ -258,6 +409,15 @@ mod app {
                 net: eth_iface,
                 sockets,
                 udp_socket: udp_handle,
+                sntp_socket: sntp_handle,
+                command_socket: command_handle,
+                syslog_socket: syslog_handle,
+                dhcp_socket: dhcp_handle,
+                ip_config,
+                time,
+                settings,
+                flash_store,
+                last_measurement: None,
             },
             Local {
                 net_clock_timer,
@@ -265,33 +425,67 @@ mod app {
                 led,
                 watchdog,
                 bme680,
+                i2c_bus,
+                shell_rx,
+                crash_report,
+                reset_report: Some(reset_report),
             },
             init::Monotonics(mono),
         )
     }
 
     extern "Rust" {
-        #[task(local = [watchdog, led])]
+        #[task(local = [watchdog, led, state: WatchdogTaskState = WatchdogTaskState::new()])]
         fn watchdog_task(ctx: watchdog_task::Context);
     }
 
     extern "Rust" {
-        #[task(local = [bme680])]
-        fn bme680_task(ctx: bme680_task::Context);
+        #[task(local = [bme680, i2c_bus, state: Bme680TaskState = Bme680TaskState::new()], shared = [settings, last_measurement], capacity = 2)]
+        fn bme680_task(ctx: bme680_task::Context, arg: Bme680SpawnArg);
     }
 
     extern "Rust" {
-        #[task(local = [state: DataManagerTaskState = DataManagerTaskState::new()], shared = [sockets, udp_socket], capacity = 8)]
+        #[task(local = [state: DataManagerTaskState = DataManagerTaskState::new(), crash_report, reset_report], shared = [eth, sockets, udp_socket, time, ip_config, settings], capacity = 8)]
         fn data_manager_task(ctx: data_manager_task::Context, arg: DataManagerSpawnArg);
     }
 
+    extern "Rust" {
+        #[task(local = [state: SntpTaskState = SntpTaskState::new()], shared = [sockets, sntp_socket, time, settings], capacity = 2)]
+        fn sntp_task(ctx: sntp_task::Context, arg: SntpSpawnArg);
+    }
+
+    extern "Rust" {
+        #[task(shared = [eth, sockets, command_socket, settings, flash_store, time, ip_config, last_measurement])]
+        fn command_task(ctx: command_task::Context);
+    }
+
+    extern "Rust" {
+        #[task(local = [state: SyslogTaskState = SyslogTaskState::new()], shared = [sockets, syslog_socket, settings, ip_config])]
+        fn syslog_task(ctx: syslog_task::Context);
+    }
+
+    extern "Rust" {
+        #[task(binds = USART6, priority = 2, local = [shell_rx, state: ShellRxState = ShellRxState::new()])]
+        fn serial_task(ctx: serial_task::Context);
+    }
+
+    extern "Rust" {
+        #[task(binds = DMA2_STREAM6)]
+        fn log_dma_task(ctx: log_dma_task::Context);
+    }
+
+    extern "Rust" {
+        #[task(shared = [eth, settings, flash_store, time, ip_config, last_measurement])]
+        fn shell_task(ctx: shell_task::Context, line: ShellLine);
+    }
+
     extern "Rust" {
         #[task(binds = SysTick, local = [net_clock_timer])]
         fn ipstack_clock_timer_task(ctx: ipstack_clock_timer_task::Context);
     }
 
     extern "Rust" {
-        #[task(shared = [eth, net, sockets], capacity = 2)]
+        #[task(shared = [eth, net, sockets, dhcp_socket, ip_config], capacity = 2)]
         fn ipstack_poll_task(ctx: ipstack_poll_task::Context);
     }
 
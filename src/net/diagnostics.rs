@@ -0,0 +1,126 @@
+//! Diagnostic messages, sent alongside the broadcast protocol on
+//! `Settings::diagnostics_port`.
+//!
+//! All messages start with a common header, fields are big endian:
+//!
+//! | Offset | Len | Field                       |
+//! |--------|-----|-----------------------------|
+//! | 0      | 4   | magic, `BEDM`               |
+//! | 4      | 1   | protocol version            |
+//! | 5      | 1   | message kind                |
+//! | 6      | 2   | device ID                   |
+//! | 8      | 12  | device serial number (UID)  |
+//!
+//! Crash report (kind 1):
+//!
+//! | Offset | Len | Field                       |
+//! |--------|-----|-----------------------------|
+//! | 20     | 4   | line                        |
+//! | 24     | 4   | column                      |
+//! | 28     | 1   | file length, N              |
+//! | 29     | N   | file                        |
+//! | 29+N   | 1   | message length, M           |
+//! | 30+N   | M   | message                     |
+//!
+//! Reset report (kind 2), sent once after boot:
+//!
+//! | Offset | Len | Field                       |
+//! |--------|-----|-----------------------------|
+//! | 20     | 1   | reset cause                 |
+//! | 21     | 32  | per-cause counters, 8 x u32 |
+//!
+//! Network statistics (kind 3), sent periodically, counters in the order of
+//! `stats::Snapshot`:
+//!
+//! | Offset | Len | Field                       |
+//! |--------|-----|-----------------------------|
+//! | 20     | 40  | counters, 10 x u32          |
+//!
+//! Link report (kind 4), sent once the link is back up after going down:
+//!
+//! | Offset | Len | Field                       |
+//! |--------|-----|-----------------------------|
+//! | 20     | 1   | link up                     |
+//! | 21     | 4   | link ups since boot         |
+//! | 25     | 4   | link downs since boot       |
+
+use crate::{
+    crash_report::{self, CrashReport},
+    net::{eth::LinkStatus, stats},
+    reset_cause::{self, ResetReport},
+    util,
+};
+use byteorder::{BigEndian, ByteOrder};
+
+pub const MAGIC: [u8; 4] = *b"BEDM";
+pub const VERSION: u8 = 1;
+
+pub const HEADER_LEN: usize = 20;
+
+pub const RESET_REPORT_LEN: usize = HEADER_LEN + 1 + 4 * reset_cause::NUM_CAUSES;
+
+pub const LINK_REPORT_LEN: usize = HEADER_LEN + 1 + 4 + 4;
+
+pub const NET_STATISTICS_LEN: usize = HEADER_LEN + 4 * stats::Snapshot::NUM_FIELDS;
+
+/// Length of the largest message
+pub const MAX_MESSAGE_LEN: usize =
+    HEADER_LEN + 8 + 1 + crash_report::MAX_FILE_LEN + 1 + crash_report::MAX_MESSAGE_LEN;
+
+#[derive(Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Debug)]
+#[repr(u8)]
+pub enum Kind {
+    CrashReport = 1,
+    ResetReport = 2,
+    NetStatistics = 3,
+    LinkReport = 4,
+}
+
+fn emit_header(kind: Kind, device_id: u16, buf: &mut [u8]) {
+    buf[0..4].copy_from_slice(&MAGIC);
+    buf[4] = VERSION;
+    buf[5] = kind as u8;
+    BigEndian::write_u16(&mut buf[6..8], device_id);
+    BigEndian::write_u32_into(&util::read_device_uid(), &mut buf[8..20]);
+}
+
+pub fn crash_report_len(report: &CrashReport) -> usize {
+    HEADER_LEN + 8 + 1 + report.file().len() + 1 + report.message().len()
+}
+
+pub fn emit_crash_report(report: &CrashReport, device_id: u16, buf: &mut [u8]) {
+    emit_header(Kind::CrashReport, device_id, buf);
+    let buf = &mut buf[HEADER_LEN..];
+    BigEndian::write_u32(&mut buf[0..4], report.line);
+    BigEndian::write_u32(&mut buf[4..8], report.column);
+
+    let file = report.file();
+    buf[8] = file.len() as u8;
+    let buf = &mut buf[9..];
+    buf[..file.len()].copy_from_slice(file);
+
+    let msg = report.message();
+    let buf = &mut buf[file.len()..];
+    buf[0] = msg.len() as u8;
+    buf[1..1 + msg.len()].copy_from_slice(msg);
+}
+
+pub fn emit_reset_report(report: &ResetReport, device_id: u16, buf: &mut [u8]) {
+    emit_header(Kind::ResetReport, device_id, buf);
+    let buf = &mut buf[HEADER_LEN..];
+    buf[0] = report.cause as u8;
+    BigEndian::write_u32_into(&report.counters, &mut buf[1..RESET_REPORT_LEN - HEADER_LEN]);
+}
+
+pub fn emit_link_report(link: &LinkStatus, device_id: u16, buf: &mut [u8]) {
+    emit_header(Kind::LinkReport, device_id, buf);
+    let buf = &mut buf[HEADER_LEN..];
+    buf[0] = link.up.into();
+    BigEndian::write_u32(&mut buf[1..5], link.ups);
+    BigEndian::write_u32(&mut buf[5..9], link.downs);
+}
+
+pub fn emit_net_statistics(stats: &stats::Snapshot, device_id: u16, buf: &mut [u8]) {
+    emit_header(Kind::NetStatistics, device_id, buf);
+    BigEndian::write_u32_into(&stats.to_array(), &mut buf[HEADER_LEN..NET_STATISTICS_LEN]);
+}
@@ -1,17 +1,24 @@
-use enc28j60::Enc28j60;
-use log::{debug, error, warn};
+use crate::{
+    config,
+    logging::{debug, error, info, warn, Debug2Format, Display2Format},
+    net::stats::{self, STATISTICS},
+};
+use core::fmt;
+use enc28j60::{bank0, bank1, common, Enc28j60};
 use smoltcp::phy::{self, Device, DeviceCapabilities, Medium};
-use smoltcp::time::Instant;
+use smoltcp::time::{Duration, Instant};
 use stm32f4xx_hal::{
-    gpio::{Input, Output, PushPull, AF5, PA8, PB12, PB13, PB14, PB15},
-    pac::SPI2,
-    spi::Spi,
+    gpio::{Input, Output, PushPull, AF5, PA8, PB1, PB12, PB13, PB14, PB15},
+    pac::{SPI2, TIM4},
+    prelude::*,
+    spi::{self, Spi},
+    timer::DelayMs,
 };
 
 type CsPin = PB12<Output<PushPull>>;
 type IntPin = PA8<Input>;
-//type ResetPin = PB1<Output<PushPull>>;
-type ResetPin = enc28j60::Unconnected;
+/// Driven by `Eth` for hard resets, the driver does soft resets
+type ResetPin = PB1<Output<PushPull>>;
 
 type SpiSckPin = PB13<AF5>;
 type SpiMisoPin = PB14<AF5>;
@@ -19,35 +26,538 @@ type SpiMosiPin = PB15<AF5>;
 type SpiPins = (SpiSckPin, SpiMisoPin, SpiMosiPin);
 type EthSpi = Spi<SPI2, SpiPins>;
 
-type Drv = Enc28j60<EthSpi, CsPin, IntPin, ResetPin>;
+type Drv = Enc28j60<EthSpi, CsPin, IntPin, enc28j60::Unconnected>;
+
+/// What the driver is made from, held by `Eth` while the driver is down
+type Parts = (EthSpi, CsPin, IntPin);
+
+/// Bytes of the controller's 8K buffer used for receiving, the rest is for transmitting
+const RX_BUFFER_SIZE: u16 = 7168;
+
+/// ESTAT, receive buffer error
+const ESTAT_BUFER: u8 = 1 << 6;
+/// EIR, transmit and receive error interrupt flags
+const EIR_TXERIF: u8 = 1 << 1;
+const EIR_RXERIF: u8 = 1 << 0;
+
+/// SPI opcodes and addresses used to probe the controller before the driver takes the bus
+const OPCODE_RCR: u8 = 0x00;
+const OPCODE_WCR: u8 = 0x40;
+const ECON1: u8 = 0x1F;
+/// In bank 3
+const EREVID: u8 = 0x12;
+
+/// PHY link state, and the number of transitions since boot
+#[derive(Copy, Clone, Eq, PartialEq, Hash, Debug)]
+pub struct LinkStatus {
+    pub up: bool,
+    pub ups: u32,
+    pub downs: u32,
+}
+
+/// Number of failures by type, and of hard resets
+#[derive(Copy, Clone, Eq, PartialEq, Hash, Debug)]
+pub struct ErrorCounters {
+    /// Getting the next packet failed
+    pub rx: u32,
+    /// Reading a packet failed
+    pub rx_read: u32,
+    /// Packets larger than the receive buffer
+    pub rx_too_big: u32,
+    pub tx: u32,
+    /// Error flags found set in ESTAT or EIR, each is cleared once counted
+    pub status: u32,
+    /// Health checks that found received packets waiting and the read pointer not moving
+    pub rx_stalled: u32,
+    /// Health checks that found ERXFCON changed, the controller lost its configuration
+    pub config: u32,
+    pub resets: u32,
+    /// Failed initializations, after a reset or at startup
+    pub init: u32,
+}
+
+impl ErrorCounters {
+    const fn new() -> Self {
+        Self {
+            rx: 0,
+            rx_read: 0,
+            rx_too_big: 0,
+            tx: 0,
+            status: 0,
+            rx_stalled: 0,
+            config: 0,
+            resets: 0,
+            init: 0,
+        }
+    }
+}
+
+impl fmt::Display for ErrorCounters {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "rx: {}, rx read: {}, rx too big: {}, tx: {}, status: {}, rx stalled: {}, \
+            config: {}, resets: {}, init: {}",
+            self.rx,
+            self.rx_read,
+            self.rx_too_big,
+            self.tx,
+            self.status,
+            self.rx_stalled,
+            self.config,
+            self.resets,
+            self.init
+        )
+    }
+}
+
+/// Failures per health check interval, to detect a wedged controller.
+/// A side is failing from the first interval with a failure, until an interval
+/// without any or a success.
+struct Health {
+    counters: ErrorCounters,
+    rx_failed: bool,
+    tx_failed: bool,
+    rx_failing_since: Option<Instant>,
+    tx_failing_since: Option<Instant>,
+    next_check: Instant,
+    /// The receive filter configured by the driver
+    erxfcon: u8,
+    /// ERXRDPT at the last check, when packets were waiting
+    rx_waiting_at: Option<u16>,
+    /// Consecutive failed initializations, for the retry backoff
+    init_failures: u32,
+}
+
+impl Health {
+    fn rx_error(&mut self) {
+        self.rx_failed = true;
+    }
+
+    fn tx_error(&mut self) {
+        self.tx_failed = true;
+    }
+
+    fn rx_ok(&mut self) {
+        self.rx_failed = false;
+        self.rx_failing_since = None;
+    }
+
+    fn tx_ok(&mut self) {
+        self.tx_failed = false;
+        self.tx_failing_since = None;
+    }
+
+    /// End the check interval, returns true when a side has been failing
+    /// without any progress for longer than the timeout
+    fn stuck(&mut self, now: Instant) -> bool {
+        let timeout = Duration::from_millis(config::ETH_STUCK_TIMEOUT_MS.into());
+        let sides = [
+            (&mut self.rx_failed, &mut self.rx_failing_since),
+            (&mut self.tx_failed, &mut self.tx_failing_since),
+        ];
+        let mut stuck = false;
+        for (failed, failing_since) in sides {
+            if core::mem::take(failed) {
+                let since = *failing_since.get_or_insert(now);
+                stuck |= now - since >= timeout;
+            } else {
+                *failing_since = None;
+            }
+        }
+        stuck
+    }
+
+    fn clear_failures(&mut self) {
+        self.rx_ok();
+        self.tx_ok();
+    }
+}
+
+#[derive(Debug)]
+enum InitError {
+    /// EREVID read back as this, the controller isn't answering
+    NotResponding(u8),
+    Spi(spi::Error),
+    /// The driver failed, logged where it happened
+    Driver,
+}
 
 /// An ENC28J60 connected to SPI2
 pub struct Eth<'buf> {
-    drv: Drv,
+    /// `None` while the controller is down, `receive` and `transmit` have nothing to offer
+    drv: Option<Drv>,
+    /// Held while the driver is down, for the next initialization attempt
+    parts: Option<Parts>,
+    reset_pin: ResetPin,
+    delay: DelayMs<TIM4>,
+    mac_address: [u8; 6],
     rx_buffer: &'buf mut [u8],
     tx_buffer: &'buf mut [u8],
+    link: LinkStatus,
+    health: Health,
 }
 
 impl<'buf> Eth<'buf> {
     // TODO - should be 1514 ? query the driver
     pub const MTU: usize = 1500;
 
-    pub fn new(drv: Drv, rx_buffer: &'buf mut [u8], tx_buffer: &'buf mut [u8]) -> Self {
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        spi: EthSpi,
+        ncs: CsPin,
+        int: IntPin,
+        reset: ResetPin,
+        delay: DelayMs<TIM4>,
+        mac_address: [u8; 6],
+        rx_buffer: &'buf mut [u8],
+        tx_buffer: &'buf mut [u8],
+    ) -> Self {
         debug!(
             "ENC28J60: buffer length, rx {}, tx {}",
             rx_buffer.len(),
             tx_buffer.len()
         );
-        Eth {
-            drv,
+        let mut eth = Eth {
+            drv: None,
+            parts: None,
+            reset_pin: reset,
+            delay,
+            mac_address,
             rx_buffer,
             tx_buffer,
+            link: LinkStatus {
+                up: false,
+                ups: 0,
+                downs: 0,
+            },
+            health: Health {
+                counters: ErrorCounters::new(),
+                rx_failed: false,
+                tx_failed: false,
+                rx_failing_since: None,
+                tx_failing_since: None,
+                next_check: Instant::ZERO,
+                erxfcon: 0,
+                rx_waiting_at: None,
+                init_failures: 0,
+            },
+        };
+        match eth.init_driver(spi, ncs, int) {
+            Ok(()) => info!("ENC28J60: link {}", if eth.link.up { "up" } else { "down" }),
+            Err(e) => eth.init_failed(Instant::ZERO, e),
         }
+        eth
+    }
+
+    pub fn link(&self) -> LinkStatus {
+        self.link
     }
 
-    pub fn driver(&mut self) -> &mut Drv {
-        &mut self.drv
+    pub fn error_counters(&self) -> ErrorCounters {
+        self.health.counters
     }
+
+    /// Handle the ENC28J60 interrupt, returns true when the controller raised it
+    pub fn handle_interrupt(&mut self) -> bool {
+        if let Some(drv) = self.drv.as_mut() {
+            if !drv.interrupt_pending() {
+                return false;
+            }
+            drv.int_pin().clear_interrupt_pending_bit();
+            self.update_link();
+            true
+        } else {
+            // Left over from before the controller went down
+            if let Some((_, _, int)) = self.parts.as_mut() {
+                int.clear_interrupt_pending_bit();
+            }
+            false
+        }
+    }
+
+    /// Check for a PHY link change, returns the new link status when it changed
+    fn update_link(&mut self) -> Option<LinkStatus> {
+        let drv = self.drv.as_mut()?;
+        // Reading the PHY interrupt flags clears the link change interrupt
+        match drv.link_changed() {
+            Ok(false) => return None,
+            Ok(true) => (),
+            Err(e) => {
+                error!(
+                    "Failed to read the PHY interrupt flags. {:?}",
+                    Debug2Format(&e)
+                );
+                return None;
+            }
+        }
+        let up = match drv.is_link_up() {
+            Ok(up) => up,
+            Err(e) => {
+                error!("Failed to read the link state. {:?}", Debug2Format(&e));
+                return None;
+            }
+        };
+        if up == self.link.up {
+            // Changed and changed back before the interrupt was handled
+            self.link.ups = self.link.ups.wrapping_add(1);
+            self.link.downs = self.link.downs.wrapping_add(1);
+            warn!("ENC28J60: link bounced");
+            return None;
+        }
+        self.link.up = up;
+        if up {
+            self.link.ups = self.link.ups.wrapping_add(1);
+            info!("ENC28J60: link up");
+        } else {
+            self.link.downs = self.link.downs.wrapping_add(1);
+            warn!("ENC28J60: link down");
+        }
+        Some(self.link)
+    }
+
+    /// Look for a wedged controller, at most every `ETH_HEALTH_CHECK_INTERVAL_MS`,
+    /// and reset it. Initializes the controller again when it's down, with a backoff.
+    /// Called before polling the interface.
+    pub fn check_health(&mut self, now: Instant) {
+        if now < self.health.next_check {
+            return;
+        }
+        self.health.next_check =
+            now + Duration::from_millis(config::ETH_HEALTH_CHECK_INTERVAL_MS.into());
+
+        let Some(drv) = self.drv.as_mut() else {
+            self.retry_init(now);
+            return;
+        };
+        let regs = drv
+            .read_control_register(bank1::Register::ERXFCON)
+            .and_then(|erxfcon| {
+                let estat = drv.read_control_register(common::Register::ESTAT)?;
+                let eir = drv.read_control_register(common::Register::EIR)?;
+                // The error flags are sticky, clear them so each event is counted once
+                if estat & ESTAT_BUFER != 0 {
+                    drv.bit_field_clear(common::Register::ESTAT, ESTAT_BUFER)?;
+                }
+                if eir & (EIR_TXERIF | EIR_RXERIF) != 0 {
+                    drv.bit_field_clear(common::Register::EIR, eir & (EIR_TXERIF | EIR_RXERIF))?;
+                }
+                let epktcnt = drv.read_control_register(bank1::Register::EPKTCNT)?;
+                let erxrdptl = drv.read_control_register(bank0::Register::ERXRDPTL)?;
+                let erxrdpth = drv.read_control_register(bank0::Register::ERXRDPTH)?;
+                let erxrdpt = u16::from_le_bytes([erxrdptl, erxrdpth]);
+                Ok((erxfcon, estat, eir, epktcnt, erxrdpt))
+            });
+        let health = &mut self.health;
+        let reset = match regs {
+            // Registers read back as garbage, or reset to their defaults
+            Ok((erxfcon, ..)) if erxfcon != health.erxfcon => {
+                health.counters.config = health.counters.config.wrapping_add(1);
+                warn!(
+                    "ENC28J60: ERXFCON is 0x{:X}, configured 0x{:X}",
+                    erxfcon, health.erxfcon
+                );
+                true
+            }
+            Ok((_, estat, eir, epktcnt, erxrdpt)) => {
+                if estat & ESTAT_BUFER != 0 || eir & (EIR_TXERIF | EIR_RXERIF) != 0 {
+                    health.counters.status = health.counters.status.wrapping_add(1);
+                    debug!(
+                        "ENC28J60: error flags, ESTAT 0x{:X}, EIR 0x{:X}",
+                        estat, eir
+                    );
+                    if estat & ESTAT_BUFER != 0 || eir & EIR_RXERIF != 0 {
+                        health.rx_error();
+                    }
+                    if eir & EIR_TXERIF != 0 {
+                        health.tx_error();
+                    }
+                }
+                // Packets are read on every poll, a rev B controller can stop reporting
+                // them while they pile up in the buffer
+                let rx_waiting_at = (epktcnt != 0).then_some(erxrdpt);
+                if rx_waiting_at.is_some() && rx_waiting_at == health.rx_waiting_at {
+                    health.counters.rx_stalled = health.counters.rx_stalled.wrapping_add(1);
+                    debug!(
+                        "ENC28J60: {} packets waiting, ERXRDPT 0x{:X}",
+                        epktcnt, erxrdpt
+                    );
+                    health.rx_error();
+                }
+                health.rx_waiting_at = rx_waiting_at;
+                health.stuck(now)
+            }
+            Err(e) => {
+                error!(
+                    "Failed to read the status registers. {:?}",
+                    Debug2Format(&e)
+                );
+                health.rx_error();
+                health.stuck(now)
+            }
+        };
+
+        if reset {
+            warn!(
+                "ENC28J60: stuck, resetting. Errors {}",
+                Display2Format(&health.counters)
+            );
+            self.reset(now);
+        }
+    }
+
+    /// Hard reset via the reset pin and re-initialize the driver
+    fn reset(&mut self, now: Instant) {
+        self.health.counters.resets = self.health.counters.resets.wrapping_add(1);
+        let was_up = self.link.up;
+        let Some(drv) = self.drv.take() else {
+            return;
+        };
+        let (spi, ncs, int, _) = drv.free();
+        let result = self.init_driver(spi, ncs, int);
+        // The PHY renegotiates, the link change interrupt reports it coming back up
+        if was_up && !self.link.up {
+            self.link.downs = self.link.downs.wrapping_add(1);
+        }
+        match result {
+            Ok(()) => info!(
+                "ENC28J60: reset, link {}",
+                if self.link.up { "up" } else { "down" }
+            ),
+            Err(e) => self.init_failed(now, e),
+        }
+    }
+
+    /// Initialize the driver again while the controller is down
+    fn retry_init(&mut self, now: Instant) {
+        let Some((spi, ncs, int)) = self.parts.take() else {
+            return;
+        };
+        let failures = self.health.init_failures;
+        match self.init_driver(spi, ncs, int) {
+            Ok(()) => info!(
+                "ENC28J60: initialized after {} failures, link {}",
+                failures,
+                if self.link.up { "up" } else { "down" }
+            ),
+            Err(e) => self.init_failed(now, e),
+        }
+    }
+
+    fn init_failed(&mut self, now: Instant, e: InitError) {
+        let health = &mut self.health;
+        health.counters.init = health.counters.init.wrapping_add(1);
+        health.init_failures = health.init_failures.saturating_add(1);
+        if self.parts.is_some() {
+            let retry_ms = init_retry_interval_ms(health.init_failures);
+            health.next_check = now + Duration::from_millis(retry_ms.into());
+            error!(
+                "ENC28J60: initialization failed, retrying in {} ms. {:?}",
+                retry_ms,
+                Debug2Format(&e)
+            );
+        } else {
+            error!(
+                "ENC28J60: initialization failed, the driver kept the SPI bus, \
+                the network is down until a restart. {:?}",
+                Debug2Format(&e)
+            );
+        }
+    }
+
+    /// Hard reset and initialize the controller. On failure `drv` is left `None`,
+    /// and the parts are kept in `parts` unless the driver consumed them.
+    fn init_driver(
+        &mut self,
+        mut spi: EthSpi,
+        mut ncs: CsPin,
+        int: IntPin,
+    ) -> Result<(), InitError> {
+        // Perform a hard reset first, then let the driver
+        // perform a soft reset by provided enc28j60::Unconnected
+        // instead of the actual reset pin
+        self.reset_pin.set_low();
+        self.delay.delay_ms(5_u8);
+        self.reset_pin.set_high();
+        self.delay.delay_ms(5_u8);
+        self.link.up = false;
+
+        // The driver doesn't give the bus back when it fails, make sure
+        // the controller answers first
+        match probe_revision(&mut spi, &mut ncs) {
+            Ok(rev) if rev != 0 && rev != 0xFF => (),
+            Ok(rev) => {
+                self.parts = Some((spi, ncs, int));
+                return Err(InitError::NotResponding(rev));
+            }
+            Err(e) => {
+                self.parts = Some((spi, ncs, int));
+                return Err(InitError::Spi(e));
+            }
+        }
+
+        let mut drv = match Enc28j60::new(
+            spi,
+            ncs,
+            int,
+            enc28j60::Unconnected,
+            &mut self.delay,
+            RX_BUFFER_SIZE,
+            self.mac_address,
+        ) {
+            Ok(drv) => drv,
+            Err(e) => {
+                error!("Failed to initialize the driver. {:?}", Debug2Format(&e));
+                return Err(InitError::Driver);
+            }
+        };
+        let configured = drv
+            .listen(enc28j60::Event::Pkt)
+            .and_then(|_| drv.listen(enc28j60::Event::Link))
+            .and_then(|_| drv.read_control_register(bank1::Register::ERXFCON));
+        let erxfcon = match configured {
+            Ok(erxfcon) => erxfcon,
+            Err(e) => {
+                error!("Failed to configure the driver. {:?}", Debug2Format(&e));
+                let (spi, ncs, int, _) = drv.free();
+                self.parts = Some((spi, ncs, int));
+                return Err(InitError::Driver);
+            }
+        };
+        match drv.is_link_up() {
+            Ok(up) => self.link.up = up,
+            Err(e) => error!("Failed to read the link state. {:?}", Debug2Format(&e)),
+        }
+        self.health.erxfcon = erxfcon;
+        self.health.rx_waiting_at = None;
+        self.health.init_failures = 0;
+        self.health.clear_failures();
+        self.drv = Some(drv);
+        Ok(())
+    }
+}
+
+/// Exponential backoff of the initialization attempts
+fn init_retry_interval_ms(consecutive_failures: u32) -> u32 {
+    let shift = consecutive_failures.min(16);
+    config::ETH_HEALTH_CHECK_INTERVAL_MS
+        .saturating_mul(1 << shift)
+        .min(config::ETH_MAX_INIT_RETRY_INTERVAL_MS)
+}
+
+/// Read EREVID with plain SPI transfers, selecting bank 3 in ECON1 first.
+/// Reads back as 0 or 0xFF when nothing answers.
+fn probe_revision(spi: &mut EthSpi, ncs: &mut CsPin) -> Result<u8, spi::Error> {
+    let mut select_bank = [OPCODE_WCR | ECON1, 0b11];
+    let mut read_revision = [OPCODE_RCR | EREVID, 0];
+    ncs.set_low();
+    let result = spi.transfer(&mut select_bank).map(|_| ());
+    ncs.set_high();
+    result?;
+    ncs.set_low();
+    let result = spi.transfer(&mut read_revision).map(|rx| rx[1]);
+    ncs.set_high();
+    result
 }
 
 impl<'buf> Device for Eth<'buf> {
@@ -55,32 +565,44 @@ impl<'buf> Device for Eth<'buf> {
     type TxToken<'a> = TxToken<'a> where Self: 'a;
 
     fn receive(&mut self, _timestamp: Instant) -> Option<(Self::RxToken<'_>, Self::TxToken<'_>)> {
-        match self.drv.next_packet() {
+        let drv = self.drv.as_mut()?;
+        let health = &mut self.health;
+        match drv.next_packet() {
             Ok(Some(packet)) => {
-                if packet.len() as usize > self.rx_buffer.len() {
+                let len = packet.len();
+                if len as usize > self.rx_buffer.len() {
+                    health.counters.rx_too_big = health.counters.rx_too_big.wrapping_add(1);
                     warn!(
                         "Dropping rx packet, too big, len {}, cap {}",
-                        packet.len(),
+                        len,
                         self.rx_buffer.len()
                     );
                     packet.ignore().unwrap();
                     None
                 } else if let Err(e) = packet.read(&mut self.rx_buffer[..]) {
-                    error!("Failed to read next packet. {e:?}");
+                    health.counters.rx_read = health.counters.rx_read.wrapping_add(1);
+                    health.rx_error();
+                    error!("Failed to read next packet. {:?}", Debug2Format(&e));
                     None
                 } else {
+                    stats::add(&STATISTICS.rx_packets, 1);
+                    stats::add(&STATISTICS.rx_bytes, u32::from(len));
+                    health.rx_ok();
                     Some((
                         RxToken(&mut self.rx_buffer[..]),
                         TxToken {
-                            phy: &mut self.drv,
+                            phy: drv,
                             buf: self.tx_buffer,
+                            health,
                         },
                     ))
                 }
             }
             Ok(None) => None,
             Err(e) => {
-                error!("Failed to receive next packet. {e:?}");
+                health.counters.rx = health.counters.rx.wrapping_add(1);
+                health.rx_error();
+                error!("Failed to receive next packet. {:?}", Debug2Format(&e));
                 None
             }
         }
@@ -88,8 +610,9 @@ impl<'buf> Device for Eth<'buf> {
 
     fn transmit(&mut self, _timestamp: Instant) -> Option<Self::TxToken<'_>> {
         Some(TxToken {
-            phy: &mut self.drv,
+            phy: self.drv.as_mut()?,
             buf: self.tx_buffer,
+            health: &mut self.health,
         })
     }
 
@@ -116,6 +639,7 @@ impl<'a> phy::RxToken for RxToken<'a> {
 pub struct TxToken<'a> {
     phy: &'a mut Drv,
     buf: &'a mut [u8],
+    health: &'a mut Health,
 }
 
 impl<'a> phy::TxToken for TxToken<'a> {
@@ -124,8 +648,18 @@ impl<'a> phy::TxToken for TxToken<'a> {
         F: FnOnce(&mut [u8]) -> R,
     {
         let result = f(&mut self.buf[..len]);
-        if let Err(e) = self.phy.transmit(&self.buf[..len]) {
-            error!("Failed to transmit packet. {e:?}");
+        match self.phy.transmit(&self.buf[..len]) {
+            Ok(()) => {
+                stats::add(&STATISTICS.tx_packets, 1);
+                stats::add(&STATISTICS.tx_bytes, len as u32);
+                self.health.tx_ok();
+            }
+            Err(e) => {
+                let counters = &mut self.health.counters;
+                counters.tx = counters.tx.wrapping_add(1);
+                self.health.tx_error();
+                error!("Failed to transmit packet. {:?}", Debug2Format(&e));
+            }
         }
         result
     }
@@ -0,0 +1,122 @@
+use crate::{
+    config,
+    logging::{info, warn, Display2Format},
+    settings::Settings,
+};
+use smoltcp::{
+    iface::Interface,
+    socket::dhcpv4::Config as DhcpConfig,
+    time::{Duration, Instant},
+    wire::{IpCidr, Ipv4Address, Ipv4Cidr},
+};
+
+pub use crate::settings::IpConfigMode as Mode;
+
+/// The current IPv4 configuration of the interface
+pub struct IpConfig {
+    mode: Mode,
+    static_cidr: Ipv4Cidr,
+    static_gateway: Ipv4Address,
+    address: Option<Ipv4Cidr>,
+    leased: bool,
+    /// When to give up waiting for a lease and use the static address
+    fallback_deadline: Option<Instant>,
+}
+
+impl IpConfig {
+    pub fn new(settings: &Settings) -> Self {
+        Self {
+            mode: settings.ip_config_mode,
+            static_cidr: settings.static_ip_cidr,
+            static_gateway: settings.static_gateway,
+            address: None,
+            leased: false,
+            fallback_deadline: None,
+        }
+    }
+
+    pub fn mode(&self) -> Mode {
+        self.mode
+    }
+
+    /// The current address, `None` while waiting for a lease
+    pub fn address(&self) -> Option<Ipv4Cidr> {
+        self.address
+    }
+
+    pub fn leased(&self) -> bool {
+        self.leased
+    }
+
+    /// Destination for the broadcast protocol, derived from the subnet when leased,
+    /// the configured address otherwise
+    pub fn broadcast_address(&self, settings: &Settings) -> Option<Ipv4Address> {
+        if self.leased {
+            self.address.and_then(|cidr| cidr.broadcast())
+        } else {
+            self.address.map(|_| settings.broadcast_address)
+        }
+    }
+
+    /// Apply the static address and default route
+    pub fn apply_static(&mut self, iface: &mut Interface) {
+        self.leased = false;
+        self.fallback_deadline = None;
+        self.set_address(iface, self.static_cidr, Some(self.static_gateway));
+    }
+
+    /// Start the fallback timer, called on every poll while waiting for a lease
+    pub fn poll_fallback(&mut self, iface: &mut Interface, now: Instant) {
+        if self.mode != Mode::Dhcp || self.address.is_some() {
+            return;
+        }
+        match self.fallback_deadline {
+            None => {
+                self.fallback_deadline =
+                    Some(now + Duration::from_secs(config::DHCP_FALLBACK_TIMEOUT_SEC.into()))
+            }
+            Some(deadline) if now >= deadline => {
+                warn!("DHCP: no lease, falling back to the static address");
+                self.apply_static(iface);
+            }
+            Some(_) => (),
+        }
+    }
+
+    pub fn apply_lease(&mut self, iface: &mut Interface, lease: &DhcpConfig) {
+        info!("DHCP: leased address {}", Display2Format(&lease.address));
+        if let Some(router) = lease.router {
+            info!("DHCP: router {}", Display2Format(&router));
+        }
+        for dns in lease.dns_servers.iter() {
+            info!("DHCP: DNS server {}", Display2Format(dns));
+        }
+        self.leased = true;
+        self.fallback_deadline = None;
+        self.set_address(iface, lease.address, lease.router);
+    }
+
+    pub fn lease_lost(&mut self, iface: &mut Interface) {
+        warn!("DHCP: lease lost");
+        self.leased = false;
+        self.address = None;
+        iface.update_ip_addrs(|addrs| addrs.clear());
+        iface.routes_mut().remove_default_ipv4_route();
+    }
+
+    fn set_address(&mut self, iface: &mut Interface, cidr: Ipv4Cidr, router: Option<Ipv4Address>) {
+        self.address = Some(cidr);
+        iface.update_ip_addrs(|addrs| {
+            addrs.clear();
+            addrs.push(IpCidr::Ipv4(cidr)).unwrap();
+        });
+        match router {
+            Some(router) => {
+                iface.routes_mut().add_default_ipv4_route(router).unwrap();
+            }
+            None => {
+                iface.routes_mut().remove_default_ipv4_route();
+            }
+        }
+    }
+}
@@ -1,5 +1,12 @@
+pub mod diagnostics;
 pub mod eth;
+pub mod ip_config;
+pub mod stats;
 pub mod storage;
+pub mod syslog;
+
+pub use bme680_env_monitor::net::sntp;
 
 pub use eth::Eth;
+pub use ip_config::IpConfig;
 pub use storage::{EthernetStorage, NetworkStorage, UdpSocketStorage};
@@ -0,0 +1,170 @@
+//! Minimal SNTPv4 client packet handling, RFC 4330
+
+use byteorder::{BigEndian, ByteOrder};
+
+pub const PORT: u16 = 123;
+
+pub const PACKET_LEN: usize = 48;
+
+/// Seconds between the NTP epoch (1900) and the unix epoch (1970)
+const NTP_UNIX_EPOCH_DELTA: u64 = 2_208_988_800;
+
+/// LI = 0, VN = 4, Mode = 3 (client)
+const CLIENT_LI_VN_MODE: u8 = (4 << 3) | 3;
+const MODE_MASK: u8 = 0x07;
+const MODE_SERVER: u8 = 4;
+
+const STRATUM: usize = 1;
+const ORIGINATE_TIMESTAMP: usize = 24;
+const TRANSMIT_TIMESTAMP: usize = 40;
+
+#[derive(Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Debug)]
+pub enum Error {
+    Truncated,
+    NotServerMode,
+    /// Kiss-o'-death packet, the server wants us to back off
+    KissOfDeath,
+    /// The response doesn't belong to our request
+    OriginateMismatch,
+    InvalidTimestamp,
+}
+
+/// Write a client request into `buf`.
+///
+/// `token` is echoed back by the server in the originate timestamp
+/// and used to match the response to this request.
+pub fn emit_request(token: u64, buf: &mut [u8]) {
+    buf[..PACKET_LEN].fill(0);
+    buf[0] = CLIENT_LI_VN_MODE;
+    BigEndian::write_u64(&mut buf[TRANSMIT_TIMESTAMP..TRANSMIT_TIMESTAMP + 8], token);
+}
+
+/// Parse a server response to the request made with `token`, returning
+/// the server's transmit time in microseconds since the unix epoch
+pub fn parse_response(token: u64, buf: &[u8]) -> Result<u64, Error> {
+    if buf.len() < PACKET_LEN {
+        return Err(Error::Truncated);
+    }
+    if buf[0] & MODE_MASK != MODE_SERVER {
+        return Err(Error::NotServerMode);
+    }
+    if buf[STRATUM] == 0 {
+        return Err(Error::KissOfDeath);
+    }
+    if BigEndian::read_u64(&buf[ORIGINATE_TIMESTAMP..ORIGINATE_TIMESTAMP + 8]) != token {
+        return Err(Error::OriginateMismatch);
+    }
+
+    let secs = u64::from(BigEndian::read_u32(
+        &buf[TRANSMIT_TIMESTAMP..TRANSMIT_TIMESTAMP + 4],
+    ));
+    let frac = u64::from(BigEndian::read_u32(
+        &buf[TRANSMIT_TIMESTAMP + 4..TRANSMIT_TIMESTAMP + 8],
+    ));
+    if secs == 0 {
+        return Err(Error::InvalidTimestamp);
+    }
+
+    // Era 1 starts in 2036, the MSB is clear once the seconds wrap
+    let secs = if secs & 0x8000_0000 == 0 {
+        secs + (1 << 32)
+    } else {
+        secs
+    };
+
+    let unix_secs = secs - NTP_UNIX_EPOCH_DELTA;
+    let micros = (frac * 1_000_000) >> 32;
+    Ok(unix_secs * 1_000_000 + micros)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TOKEN: u64 = 0x0123_4567_89AB_CDEF;
+
+    /// A server response to the request made with `TOKEN`
+    fn response(secs: u32, frac: u32) -> [u8; PACKET_LEN] {
+        let mut buf = [0; PACKET_LEN];
+        emit_request(TOKEN, &mut buf);
+        let token = BigEndian::read_u64(&buf[TRANSMIT_TIMESTAMP..TRANSMIT_TIMESTAMP + 8]);
+        buf[0] = (4 << 3) | MODE_SERVER;
+        buf[STRATUM] = 2;
+        BigEndian::write_u64(
+            &mut buf[ORIGINATE_TIMESTAMP..ORIGINATE_TIMESTAMP + 8],
+            token,
+        );
+        BigEndian::write_u32(&mut buf[TRANSMIT_TIMESTAMP..TRANSMIT_TIMESTAMP + 4], secs);
+        BigEndian::write_u32(
+            &mut buf[TRANSMIT_TIMESTAMP + 4..TRANSMIT_TIMESTAMP + 8],
+            frac,
+        );
+        buf
+    }
+
+    #[test]
+    fn request() {
+        let mut buf = [0xFF; PACKET_LEN];
+        emit_request(TOKEN, &mut buf);
+        assert_eq!(buf[0], 0x23);
+        assert!(buf[1..TRANSMIT_TIMESTAMP].iter().all(|b| *b == 0));
+        assert_eq!(
+            BigEndian::read_u64(&buf[TRANSMIT_TIMESTAMP..TRANSMIT_TIMESTAMP + 8]),
+            TOKEN
+        );
+    }
+
+    #[test]
+    fn unix_time() {
+        // 2023-11-14T22:13:20.5Z
+        let buf = response(1_700_000_000 + 2_208_988_800, 0x8000_0000);
+        assert_eq!(
+            parse_response(TOKEN, &buf),
+            Ok(1_700_000_000 * 1_000_000 + 500_000)
+        );
+    }
+
+    #[test]
+    fn era_rollover() {
+        // Era 1 starts at 2036-02-07T06:28:16Z, unix 2_085_978_496
+        let buf = response(1, 0);
+        assert_eq!(parse_response(TOKEN, &buf), Ok(2_085_978_497 * 1_000_000));
+        let buf = response(0x7FFF_FFFF, 0);
+        assert_eq!(
+            parse_response(TOKEN, &buf),
+            Ok((2_085_978_496 + 0x7FFF_FFFF) * 1_000_000)
+        );
+    }
+
+    #[test]
+    fn token_mismatch() {
+        let buf = response(3_908_988_800, 0);
+        assert_eq!(
+            parse_response(TOKEN + 1, &buf),
+            Err(Error::OriginateMismatch)
+        );
+    }
+
+    #[test]
+    fn kiss_of_death() {
+        let mut buf = response(3_908_988_800, 0);
+        buf[STRATUM] = 0;
+        assert_eq!(parse_response(TOKEN, &buf), Err(Error::KissOfDeath));
+    }
+
+    #[test]
+    fn invalid_packets() {
+        let buf = response(3_908_988_800, 0);
+        assert_eq!(
+            parse_response(TOKEN, &buf[..PACKET_LEN - 1]),
+            Err(Error::Truncated)
+        );
+
+        let mut client = buf;
+        client[0] = CLIENT_LI_VN_MODE;
+        assert_eq!(parse_response(TOKEN, &client), Err(Error::NotServerMode));
+
+        let unset = response(0, 0);
+        assert_eq!(parse_response(TOKEN, &unset), Err(Error::InvalidTimestamp));
+    }
+}
@@ -0,0 +1,111 @@
+//! Network interface statistics, counted by the ENC28J60 device and the poll
+//! task, readable from any task. The failures are counted by `Eth` in its
+//! `ErrorCounters`, snapshots include them.
+
+use crate::net::eth::ErrorCounters;
+use core::{
+    fmt,
+    sync::atomic::{AtomicU32, Ordering::Relaxed},
+};
+
+pub static STATISTICS: Statistics = Statistics::new();
+
+pub struct Statistics {
+    pub rx_packets: AtomicU32,
+    pub rx_bytes: AtomicU32,
+    pub tx_packets: AtomicU32,
+    pub tx_bytes: AtomicU32,
+    pub polls: AtomicU32,
+    /// Polls that processed packets or changed socket state
+    pub active_polls: AtomicU32,
+}
+
+impl Statistics {
+    const fn new() -> Self {
+        Self {
+            rx_packets: AtomicU32::new(0),
+            rx_bytes: AtomicU32::new(0),
+            tx_packets: AtomicU32::new(0),
+            tx_bytes: AtomicU32::new(0),
+            polls: AtomicU32::new(0),
+            active_polls: AtomicU32::new(0),
+        }
+    }
+
+    /// The counters along with the `Eth` failure counters
+    pub fn snapshot(&self, errors: &ErrorCounters) -> Snapshot {
+        Snapshot {
+            rx_packets: self.rx_packets.load(Relaxed),
+            rx_bytes: self.rx_bytes.load(Relaxed),
+            tx_packets: self.tx_packets.load(Relaxed),
+            tx_bytes: self.tx_bytes.load(Relaxed),
+            rx_dropped: errors.rx_too_big,
+            rx_errors: errors.rx.wrapping_add(errors.rx_read),
+            tx_errors: errors.tx,
+            resets: errors.resets,
+            polls: self.polls.load(Relaxed),
+            active_polls: self.active_polls.load(Relaxed),
+        }
+    }
+}
+
+/// Counters wrap, add with `Relaxed` ordering
+pub fn add(counter: &AtomicU32, n: u32) {
+    counter.fetch_add(n, Relaxed);
+}
+
+/// The counters at one point in time
+#[derive(Copy, Clone, Eq, PartialEq, Hash, Debug)]
+pub struct Snapshot {
+    pub rx_packets: u32,
+    pub rx_bytes: u32,
+    pub tx_packets: u32,
+    pub tx_bytes: u32,
+    /// Received packets larger than the receive buffer
+    pub rx_dropped: u32,
+    pub rx_errors: u32,
+    pub tx_errors: u32,
+    /// ENC28J60 hard resets
+    pub resets: u32,
+    pub polls: u32,
+    pub active_polls: u32,
+}
+
+impl Snapshot {
+    pub const NUM_FIELDS: usize = 10;
+
+    /// In the order of the fields
+    pub fn to_array(&self) -> [u32; Self::NUM_FIELDS] {
+        [
+            self.rx_packets,
+            self.rx_bytes,
+            self.tx_packets,
+            self.tx_bytes,
+            self.rx_dropped,
+            self.rx_errors,
+            self.tx_errors,
+            self.resets,
+            self.polls,
+            self.active_polls,
+        ]
+    }
+}
+
+impl fmt::Display for Snapshot {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "rx {} packets {} bytes, tx {} packets {} bytes, rx dropped {}, rx errors {}, tx errors {}, resets {}, polls {} ({} active)",
+            self.rx_packets,
+            self.rx_bytes,
+            self.tx_packets,
+            self.tx_bytes,
+            self.rx_dropped,
+            self.rx_errors,
+            self.tx_errors,
+            self.resets,
+            self.polls,
+            self.active_polls
+        )
+    }
+}
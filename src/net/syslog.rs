@@ -0,0 +1,154 @@
+//! RFC 5424 syslog over UDP, the second log sink.
+//!
+//! The logger queues the records at or above the syslog level, from any
+//! context, and the syslog task sends them to `Settings::syslog_address`:
+//!
+//! ```text
+//! <PRI>1 TIMESTAMP HOSTNAME APP-NAME - MSGID - MSG
+//! <132>1 2023-06-01T12:00:00Z 192.168.1.38 bme680-env-monitor - tasks::data_manager - Socket cannot send
+//! ```
+//!
+//! The timestamp is `-` until the time is set, the hostname is the IP address
+//! and the message ID is the module.
+
+use crate::{
+    config,
+    log_buffer::LogBuffer,
+    logger,
+    time_service::{self, UtcDateTime},
+    util::TruncatingWriter,
+};
+use byteorder::{ByteOrder, LittleEndian};
+use core::fmt::Write;
+use log::{Level, Record};
+use smoltcp::wire::Ipv4Address;
+
+/// Length of the largest message, longer records are truncated
+pub const MAX_MESSAGE_LEN: usize = 384;
+
+/// RFC 5424 limit
+const MAX_MSGID_LEN: usize = 32;
+
+/// Queued records: length u16, severity u8, unix time u32 (0 when unknown),
+/// module length u8, module, message
+const ENTRY_HEADER_LEN: usize = 8;
+
+pub const MAX_ENTRY_LEN: usize = ENTRY_HEADER_LEN + MAX_MSGID_LEN + config::LOG_RECORD_MAX_LEN;
+
+static BUFFER: LogBuffer<{ config::SYSLOG_BUFFER_LEN }> = LogBuffer::new();
+
+/// A queued record
+pub struct Entry<'a> {
+    severity: u8,
+    unix_time: Option<u32>,
+    module: &'a str,
+    message: &'a str,
+}
+
+/// Total number of bytes dropped because the queue was full
+pub fn dropped() -> u32 {
+    BUFFER.dropped()
+}
+
+/// Queue a record, from any context
+pub fn push(record: &Record) {
+    let mut buf = [0_u8; MAX_ENTRY_LEN];
+    let module = record
+        .module_path()
+        .map(logger::relative_module)
+        .unwrap_or("-");
+    let module = utf8_prefix(&module.as_bytes()[..module.len().min(MAX_MSGID_LEN)]);
+    buf[2] = severity(record.level());
+    LittleEndian::write_u32(
+        &mut buf[3..7],
+        time_service::unix_time_now().unwrap_or(0) as u32,
+    );
+    buf[7] = module.len() as u8;
+    let message_start = ENTRY_HEADER_LEN + module.len();
+    buf[ENTRY_HEADER_LEN..message_start].copy_from_slice(module.as_bytes());
+    let mut w = TruncatingWriter::new(&mut buf[message_start..]);
+    write!(w, "{}", record.args()).ok();
+    let len = message_start + w.len();
+    LittleEndian::write_u16(&mut buf[0..2], len as u16);
+    BUFFER.write(&buf[..len]);
+}
+
+/// Take the oldest queued record, syslog task only
+pub fn pop(buf: &mut [u8; MAX_ENTRY_LEN]) -> Option<Entry<'_>> {
+    match BUFFER.read(&mut buf[..2]) {
+        0 => return None,
+        2 => (),
+        _ => return lost_framing(),
+    }
+    let len = usize::from(LittleEndian::read_u16(&buf[..2]));
+    if !(ENTRY_HEADER_LEN..=MAX_ENTRY_LEN).contains(&len) {
+        return lost_framing();
+    }
+    // Records are committed whole, the rest of it is there
+    if BUFFER.read(&mut buf[2..len]) != len - 2 {
+        return lost_framing();
+    }
+    let message_start = ENTRY_HEADER_LEN + usize::from(buf[7]);
+    if message_start > len {
+        return lost_framing();
+    }
+    Some(Entry {
+        severity: buf[2],
+        unix_time: Some(LittleEndian::read_u32(&buf[3..7])).filter(|t| *t != 0),
+        module: utf8_prefix(&buf[ENTRY_HEADER_LEN..message_start]),
+        message: utf8_prefix(&buf[message_start..len]),
+    })
+}
+
+/// Nothing after a bad header can be trusted, drop the queue
+fn lost_framing<'a>() -> Option<Entry<'a>> {
+    clear();
+    None
+}
+
+/// Discard the queued records
+pub fn clear() {
+    loop {
+        let n = BUFFER.readable().len();
+        if n == 0 {
+            break;
+        }
+        BUFFER.consume(n);
+    }
+}
+
+/// Format a record as a syslog message, returns its length
+pub fn emit(entry: &Entry, hostname: Ipv4Address, buf: &mut [u8]) -> usize {
+    let mut w = TruncatingWriter::new(buf);
+    write!(w, "<{}>1 ", config::SYSLOG_FACILITY * 8 + entry.severity).ok();
+    match entry.unix_time {
+        Some(t) => write!(w, "{} ", UtcDateTime::from_unix(u64::from(t))).ok(),
+        None => w.write_str("- ").ok(),
+    };
+    write!(
+        w,
+        "{hostname} {} - {} - {}",
+        crate::built_info::PKG_NAME,
+        entry.module,
+        entry.message
+    )
+    .ok();
+    w.len()
+}
+
+const fn severity(level: Level) -> u8 {
+    match level {
+        Level::Error => 3,
+        Level::Warn => 4,
+        Level::Info => 6,
+        Level::Debug | Level::Trace => 7,
+    }
+}
+
+/// Truncation can split a character
+fn utf8_prefix(bytes: &[u8]) -> &str {
+    match core::str::from_utf8(bytes) {
+        Ok(s) => s,
+        Err(e) => core::str::from_utf8(&bytes[..e.valid_up_to()]).unwrap_or_default(),
+    }
+}
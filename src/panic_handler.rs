@@ -19,7 +19,14 @@ fn panic(info: &PanicInfo) -> ! {
     }
     PANICKED.store(true, Ordering::Relaxed);
 
-    let w = unsafe { crate::logger::get_logger() };
+    crate::crash_report::record_panic(info);
+
+    #[cfg(feature = "defmt")]
+    defmt::error!("PANIC {}", defmt::Display2Format(info));
+
+    // Buffered records first, then the panic message bypassing the buffer
+    crate::logger::flush_blocking();
+    let w = &mut crate::logger::BlockingWriter;
     writeln!(w, "\n********************************\r").ok();
     writeln!(w, "PANIC\r").ok();
     writeln!(w, "{info}\r").ok();
@@ -0,0 +1,108 @@
+//! Reset cause decoding, from the RCC_CSR reset flags.
+//!
+//! The per-cause counters are kept in the RTC backup registers, like the
+//! calendar they survive a loss of main power as long as VBAT is supplied.
+
+use crate::rtc::Rtc;
+use core::fmt;
+use stm32f4xx_hal::pac;
+
+const MAGIC: u32 = 0x8E5E_7C05;
+
+/// Backup register holding `MAGIC`, the counters are in the ones that follow
+const BACKUP_REGISTER: usize = 0;
+
+pub const NUM_CAUSES: usize = 8;
+
+#[derive(Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Debug)]
+#[repr(u8)]
+pub enum ResetCause {
+    /// No flag set, the flags were cleared without a reset in between
+    Unknown = 0,
+    PowerOn = 1,
+    BrownOut = 2,
+    /// External NRST pin
+    Pin = 3,
+    /// `SCB::sys_reset` or the debugger
+    Software = 4,
+    IndependentWatchdog = 5,
+    WindowWatchdog = 6,
+    LowPower = 7,
+}
+
+impl ResetCause {
+    /// Read the reset flags and clear them for the next reset.
+    ///
+    /// Several flags can be set at once (e.g. a power-on also sets the pin and
+    /// brown-out flags), the most specific one wins.
+    pub fn read_and_clear() -> Self {
+        let rcc = unsafe { &*pac::RCC::ptr() };
+        let csr = rcc.csr.read();
+        let cause = if csr.wdgrstf().bit_is_set() {
+            ResetCause::IndependentWatchdog
+        } else if csr.wwdgrstf().bit_is_set() {
+            ResetCause::WindowWatchdog
+        } else if csr.lpwrrstf().bit_is_set() {
+            ResetCause::LowPower
+        } else if csr.sftrstf().bit_is_set() {
+            ResetCause::Software
+        } else if csr.porrstf().bit_is_set() {
+            ResetCause::PowerOn
+        } else if csr.borrstf().bit_is_set() {
+            ResetCause::BrownOut
+        } else if csr.padrstf().bit_is_set() {
+            ResetCause::Pin
+        } else {
+            ResetCause::Unknown
+        };
+        rcc.csr.modify(|_, w| w.rmvf().set_bit());
+        cause
+    }
+}
+
+impl fmt::Display for ResetCause {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let s = match self {
+            ResetCause::Unknown => "unknown",
+            ResetCause::PowerOn => "power-on",
+            ResetCause::BrownOut => "brown-out",
+            ResetCause::Pin => "pin",
+            ResetCause::Software => "software",
+            ResetCause::IndependentWatchdog => "independent watchdog",
+            ResetCause::WindowWatchdog => "window watchdog",
+            ResetCause::LowPower => "low-power",
+        };
+        f.write_str(s)
+    }
+}
+
+/// The cause of the last reset and how many times each cause has occurred
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub struct ResetReport {
+    pub cause: ResetCause,
+    /// Indexed by `ResetCause as usize`
+    pub counters: [u32; NUM_CAUSES],
+}
+
+impl ResetReport {
+    /// Count `cause` in the backup registers, the RTC must be set up first
+    pub fn new(cause: ResetCause, rtc: &mut Rtc) -> Self {
+        let counters_start = BACKUP_REGISTER + 1;
+        if rtc.backup_register(BACKUP_REGISTER) != MAGIC {
+            // First boot, or the backup domain was reset
+            for idx in counters_start..counters_start + NUM_CAUSES {
+                rtc.set_backup_register(idx, 0);
+            }
+            rtc.set_backup_register(BACKUP_REGISTER, MAGIC);
+        }
+
+        let idx = counters_start + cause as usize;
+        rtc.set_backup_register(idx, rtc.backup_register(idx).saturating_add(1));
+
+        let mut counters = [0; NUM_CAUSES];
+        for (i, c) in counters.iter_mut().enumerate() {
+            *c = rtc.backup_register(counters_start + i);
+        }
+        ResetReport { cause, counters }
+    }
+}
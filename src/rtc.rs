@@ -0,0 +1,210 @@
+//! On-chip RTC calendar.
+//!
+//! The HAL's RTC driver resets the backup domain on init, which would lose
+//! the calendar on every watchdog reset, so this only (re)configures the RTC
+//! when it isn't already running.
+
+use crate::time_service::{days_from_civil, UtcDateTime};
+use cortex_m::asm;
+use stm32f4xx_hal::{
+    pac::{self, PWR, RTC},
+    rcc::Clocks,
+};
+
+/// Unlock sequence for the RTC write protection register
+const WPR_KEY_1: u8 = 0xCA;
+const WPR_KEY_2: u8 = 0x53;
+
+/// How long to wait for the LSE crystal to start
+const LSE_STARTUP_TIMEOUT_MS: u32 = 1000;
+
+#[derive(Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Debug)]
+pub enum ClockSource {
+    /// External 32.768 kHz crystal
+    Lse,
+    /// Internal ~32 kHz RC oscillator, much less accurate
+    Lsi,
+}
+
+pub struct Rtc {
+    rtc: RTC,
+    clock_source: ClockSource,
+}
+
+impl Rtc {
+    /// Enable the RTC, keeping the calendar if it's already running
+    pub fn new(rtc: RTC, pwr: PWR, clocks: &Clocks) -> Self {
+        let rcc = unsafe { &*pac::RCC::ptr() };
+
+        // Enable access to the backup domain
+        rcc.apb1enr.modify(|_, w| w.pwren().set_bit());
+        pwr.cr.modify(|_, w| w.dbp().set_bit());
+
+        let bdcr = rcc.bdcr.read();
+        let running_on_lse = bdcr.rtcen().bit_is_set() && bdcr.rtcsel().is_lse();
+        let running_on_lsi = bdcr.rtcen().bit_is_set() && bdcr.rtcsel().is_lsi();
+        let already_running = running_on_lse || running_on_lsi;
+        let clock_source = if running_on_lse {
+            ClockSource::Lse
+        } else if running_on_lsi {
+            // LSI is not part of the backup domain, it's disabled on every reset
+            Self::enable_lsi();
+            ClockSource::Lsi
+        } else {
+            // Start from scratch, the source can only be changed after a backup domain reset
+            rcc.bdcr.modify(|_, w| w.bdrst().set_bit());
+            rcc.bdcr.modify(|_, w| w.bdrst().clear_bit());
+
+            let clock_source = if Self::enable_lse(clocks) {
+                ClockSource::Lse
+            } else {
+                Self::enable_lsi();
+                ClockSource::Lsi
+            };
+            rcc.bdcr.modify(|_, w| match clock_source {
+                ClockSource::Lse => w.rtcsel().lse().rtcen().set_bit(),
+                ClockSource::Lsi => w.rtcsel().lsi().rtcen().set_bit(),
+            });
+            clock_source
+        };
+
+        let mut rtc = Rtc { rtc, clock_source };
+        if !already_running {
+            rtc.modify(|regs| {
+                // 1 Hz calendar clock, ck_spre = f / ((PREDIV_A + 1) * (PREDIV_S + 1))
+                let prediv_s = match clock_source {
+                    ClockSource::Lse => 255,
+                    ClockSource::Lsi => 249,
+                };
+                regs.prer
+                    .write(|w| unsafe { w.prediv_s().bits(prediv_s).prediv_a().bits(127) });
+            });
+        }
+        rtc
+    }
+
+    pub fn clock_source(&self) -> ClockSource {
+        self.clock_source
+    }
+
+    /// Read one of the 20 backup registers, retained with the calendar
+    pub fn backup_register(&self, idx: usize) -> u32 {
+        self.rtc.bkpr[idx].read().bits()
+    }
+
+    pub fn set_backup_register(&mut self, idx: usize, value: u32) {
+        self.rtc.bkpr[idx].write(|w| unsafe { w.bits(value) });
+    }
+
+    /// Seconds since the unix epoch, `None` if the calendar was never set
+    pub fn unix_time(&self) -> Option<u64> {
+        if self.rtc.isr.read().inits().bit_is_clear() {
+            return None;
+        }
+
+        // Reading TR locks DR until it's read
+        let tr = self.rtc.tr.read();
+        let dr = self.rtc.dr.read();
+        let year = 2000 + u32::from(bcd_to_bin(dr.yt().bits(), dr.yu().bits()));
+        let month = bcd_to_bin(u8::from(dr.mt().bit()), dr.mu().bits());
+        let day = bcd_to_bin(dr.dt().bits(), dr.du().bits());
+        let hour = bcd_to_bin(tr.ht().bits(), tr.hu().bits());
+        let minute = bcd_to_bin(tr.mnt().bits(), tr.mnu().bits());
+        let second = bcd_to_bin(tr.st().bits(), tr.su().bits());
+
+        let days = days_from_civil(year, month.into(), day.into());
+        Some(days * 86_400 + u64::from(hour) * 3_600 + u64::from(minute) * 60 + u64::from(second))
+    }
+
+    /// Set the calendar, only years 2000..=2099 are representable
+    pub fn set_unix_time(&mut self, unix_secs: u64) {
+        let dt = UtcDateTime::from_unix(unix_secs);
+        let year = dt.year.clamp(2000, 2099) - 2000;
+        self.modify(|regs| {
+            let (ht, hu) = bin_to_bcd(dt.hour);
+            let (mnt, mnu) = bin_to_bcd(dt.minute);
+            let (st, su) = bin_to_bcd(dt.second);
+            regs.tr.write(|w| unsafe {
+                w.pm()
+                    .clear_bit()
+                    .ht()
+                    .bits(ht)
+                    .hu()
+                    .bits(hu)
+                    .mnt()
+                    .bits(mnt)
+                    .mnu()
+                    .bits(mnu)
+                    .st()
+                    .bits(st)
+                    .su()
+                    .bits(su)
+            });
+
+            let (yt, yu) = bin_to_bcd(year as u8);
+            let (mt, mu) = bin_to_bcd(dt.month);
+            let (dt_, du) = bin_to_bcd(dt.day);
+            regs.dr.write(|w| unsafe {
+                w.yt()
+                    .bits(yt)
+                    .yu()
+                    .bits(yu)
+                    .wdu()
+                    .bits(dt.weekday)
+                    .mt()
+                    .bit(mt != 0)
+                    .mu()
+                    .bits(mu)
+                    .dt()
+                    .bits(dt_)
+                    .du()
+                    .bits(du)
+            });
+        });
+    }
+
+    /// Run `f` with the registers unlocked and the RTC in initialization mode
+    fn modify<F: FnOnce(&RTC)>(&mut self, f: F) {
+        self.rtc.wpr.write(|w| unsafe { w.key().bits(WPR_KEY_1) });
+        self.rtc.wpr.write(|w| unsafe { w.key().bits(WPR_KEY_2) });
+        self.rtc.isr.modify(|_, w| w.init().set_bit());
+        while self.rtc.isr.read().initf().bit_is_clear() {}
+
+        f(&self.rtc);
+
+        self.rtc.isr.modify(|_, w| w.init().clear_bit());
+        // Wait for the shadow registers to be updated
+        self.rtc.isr.modify(|_, w| w.rsf().clear_bit());
+        while self.rtc.isr.read().rsf().bit_is_clear() {}
+        self.rtc.wpr.write(|w| unsafe { w.key().bits(0xFF) });
+    }
+
+    /// Returns false if the crystal didn't start, likely not populated
+    fn enable_lse(clocks: &Clocks) -> bool {
+        let rcc = unsafe { &*pac::RCC::ptr() };
+        rcc.bdcr.modify(|_, w| w.lseon().set_bit());
+        let cycles_per_ms = clocks.sysclk().raw() / 1_000;
+        for _ in 0..LSE_STARTUP_TIMEOUT_MS {
+            if rcc.bdcr.read().lserdy().bit_is_set() {
+                return true;
+            }
+            asm::delay(cycles_per_ms);
+        }
+        rcc.bdcr.modify(|_, w| w.lseon().clear_bit());
+        false
+    }
+
+    fn enable_lsi() {
+        let rcc = unsafe { &*pac::RCC::ptr() };
+        rcc.csr.modify(|_, w| w.lsion().set_bit());
+        while rcc.csr.read().lsirdy().bit_is_clear() {}
+    }
+}
+
+fn bcd_to_bin(tens: u8, units: u8) -> u8 {
+    tens * 10 + units
+}
+
+fn bin_to_bcd(value: u8) -> (u8, u8) {
+    (value / 10, value % 10)
+}
@@ -1,14 +1,14 @@
 use bme680::{
     Error, FieldData, I2CAddress, IIRFilterSize, OversamplingSetting, PowerMode, SettingsBuilder,
 };
-use core::fmt;
+use core::{fmt, ops::RangeInclusive, time::Duration};
 use stm32f4xx_hal::{
     gpio::{OpenDrain, AF4, AF9, PB10, PB3},
     hal::blocking::{
         delay::DelayMs,
         i2c::{Read, Write},
     },
-    i2c::I2c,
+    i2c::{Error as I2cError, I2c},
     pac::I2C2,
 };
 
@@ -18,6 +18,73 @@ pub struct Measurement {
     pub temperature: i32,
     /// The relative humidity in centipercent
     pub humidity: u16,
+    /// The barometric pressure in pascals
+    pub pressure: u32,
+    /// True if the pressure reading is within the sensor's operating range
+    pub pressure_valid: bool,
+    /// The gas resistance in ohms
+    pub gas_resistance: u32,
+    /// True if a gas measurement was performed and the heater was stable
+    pub gas_valid: bool,
+    /// True if the gas heater reached its target temperature
+    pub heater_stable: bool,
+}
+
+/// Source of the ambient temperature used by the sensor to compute the
+/// heater resistance for the target temperature
+#[derive(Copy, Clone, Eq, PartialEq, Hash, Debug)]
+pub enum AmbientTemperatureSource {
+    /// A fixed ambient temperature in degrees C
+    Fixed(i8),
+    /// The most recent temperature measurement
+    Measured,
+}
+
+/// Gas sensor hot plate heater configuration
+#[derive(Copy, Clone, Eq, PartialEq, Hash, Debug)]
+pub struct HeaterProfile {
+    /// The heater target temperature in degrees C, typically 200..=400
+    pub target_temperature: u16,
+    /// The heating duration in milliseconds, max 4032
+    pub duration_ms: u16,
+    pub ambient_temperature: AmbientTemperatureSource,
+}
+
+/// Classification of measurement errors
+#[derive(Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Debug)]
+pub enum Fault {
+    /// The sensor didn't acknowledge
+    Nack,
+    /// Another master, or noise, took the bus
+    ArbitrationLoss,
+    /// Misplaced START or STOP condition
+    Bus,
+    Timeout,
+    /// Other I2C errors
+    I2c,
+    /// The sensor responded with an unexpected chip ID
+    DeviceNotFound,
+    /// The measurement didn't complete in time
+    NoNewData,
+    /// Other driver errors
+    Driver,
+}
+
+impl Fault {
+    pub fn from_error(e: &Error<I2cError, I2cError>) -> Self {
+        match e {
+            Error::I2CRead(e) | Error::I2CWrite(e) => match e {
+                I2cError::NoAcknowledge(_) => Fault::Nack,
+                I2cError::ArbitrationLoss => Fault::ArbitrationLoss,
+                I2cError::Bus => Fault::Bus,
+                I2cError::Timeout => Fault::Timeout,
+                _ => Fault::I2c,
+            },
+            Error::DeviceNotFound => Fault::DeviceNotFound,
+            Error::NoNewData => Fault::NoNewData,
+            _ => Fault::Driver,
+        }
+    }
 }
 
 pub type DefaultI2cPins = (PB10<AF4<OpenDrain>>, PB3<AF9<OpenDrain>>);
@@ -26,6 +93,11 @@ pub type DefaultI2c<PINS = DefaultI2cPins> = I2c<I2C2, PINS>;
 pub struct Bme680<D, I2C = DefaultI2c> {
     drv: bme680::Bme680<I2C, D>,
     delay: D,
+    heater_profile: Option<HeaterProfile>,
+    /// The ambient temperature currently programmed into the sensor
+    ambient_temperature: i8,
+    /// Time it takes the sensor to complete a TPHG measurement cycle
+    profile_duration: Duration,
 }
 
 impl<D, I2C> Bme680<D, I2C>
@@ -33,20 +105,53 @@ where
     I2C: Read + Write,
     D: DelayMs<u8>,
 {
+    /// Ambient temperature used until the first measurement is available
+    const DEFAULT_AMBIENT_TEMPERATURE: i8 = 25;
+
     pub fn new(
         i2c: I2C,
         mut delay: D,
+        heater_profile: Option<HeaterProfile>,
     ) -> Result<Self, Error<<I2C as Read>::Error, <I2C as Write>::Error>> {
-        let mut drv = bme680::Bme680::init(i2c, &mut delay, I2CAddress::Secondary)?;
-        let settings = SettingsBuilder::new()
-            .with_humidity_oversampling(OversamplingSetting::OS2x)
-            .with_pressure_oversampling(OversamplingSetting::OS4x)
-            .with_temperature_oversampling(OversamplingSetting::OS8x)
-            .with_temperature_filter(IIRFilterSize::Size3)
-            .build();
-        drv.set_sensor_settings(&mut delay, settings)?;
-        drv.set_sensor_mode(&mut delay, PowerMode::ForcedMode)?;
-        Ok(Self { drv, delay })
+        let drv = bme680::Bme680::init(i2c, &mut delay, I2CAddress::Secondary)?;
+        let mut sensor = Self {
+            drv,
+            delay,
+            heater_profile: None,
+            ambient_temperature: Self::DEFAULT_AMBIENT_TEMPERATURE,
+            profile_duration: Duration::ZERO,
+        };
+        sensor.set_heater_profile(heater_profile)?;
+        sensor
+            .drv
+            .set_sensor_mode(&mut sensor.delay, PowerMode::ForcedMode)?;
+        Ok(sensor)
+    }
+
+    /// Re-initialize the driver, after the bus has been recovered,
+    /// and restore the settings
+    pub fn reinit(
+        &mut self,
+        i2c: I2C,
+    ) -> Result<(), Error<<I2C as Read>::Error, <I2C as Write>::Error>> {
+        self.drv = bme680::Bme680::init(i2c, &mut self.delay, I2CAddress::Secondary)?;
+        self.apply_settings()?;
+        self.drv
+            .set_sensor_mode(&mut self.delay, PowerMode::ForcedMode)
+    }
+
+    /// Change the gas heater profile, `None` disables the gas measurement
+    pub fn set_heater_profile(
+        &mut self,
+        heater_profile: Option<HeaterProfile>,
+    ) -> Result<(), Error<<I2C as Read>::Error, <I2C as Write>::Error>> {
+        self.heater_profile = heater_profile;
+        if let Some(AmbientTemperatureSource::Fixed(t)) =
+            heater_profile.map(|p| p.ambient_temperature)
+        {
+            self.ambient_temperature = t;
+        }
+        self.apply_settings()
     }
 
     pub fn measure(
@@ -54,22 +159,75 @@ where
     ) -> Result<Measurement, Error<<I2C as Read>::Error, <I2C as Write>::Error>> {
         self.drv
             .set_sensor_mode(&mut self.delay, PowerMode::ForcedMode)?;
-        // TODO does the bme680 driver do the correct delay??
+
+        // Wait for the TPHG cycle to complete, including the heater duration,
+        // the driver only polls briefly for new data
         // https://github.com/boschsensortec/BME68x-Sensor-API/blob/6dab330cb5727006d5046f9eebf357f8909c0ef6/examples/forced_mode/forced_mode.c#L67-L78
-        //
-        // https://docs.rs/bme680/0.6.0/src/bme680/lib.rs.html#940-996
-        //
-        //
+        let mut remaining_ms = self.profile_duration.as_millis();
+        while remaining_ms != 0 {
+            let ms = remaining_ms.min(u8::MAX.into());
+            self.delay.delay_ms(ms as u8);
+            remaining_ms -= ms;
+        }
+
         let (data, _state) = self.drv.get_sensor_data(&mut self.delay)?;
-        Ok(Measurement::from(data))
+        let mut measurement = Measurement::from(data);
+        if self.heater_profile.is_none() {
+            measurement.gas_valid = false;
+        }
+
+        if let Some(HeaterProfile {
+            ambient_temperature: AmbientTemperatureSource::Measured,
+            ..
+        }) = self.heater_profile
+        {
+            let t = (measurement.temperature / 100).clamp(i8::MIN.into(), i8::MAX.into()) as i8;
+            if t != self.ambient_temperature {
+                self.ambient_temperature = t;
+                self.apply_settings()?;
+            }
+        }
+
+        Ok(measurement)
+    }
+
+    fn apply_settings(&mut self) -> Result<(), Error<<I2C as Read>::Error, <I2C as Write>::Error>> {
+        let builder = SettingsBuilder::new()
+            .with_humidity_oversampling(OversamplingSetting::OS2x)
+            .with_pressure_oversampling(OversamplingSetting::OS4x)
+            .with_temperature_oversampling(OversamplingSetting::OS8x)
+            .with_temperature_filter(IIRFilterSize::Size3);
+        let settings = match self.heater_profile {
+            Some(p) => builder
+                .with_gas_measurement(
+                    Duration::from_millis(p.duration_ms.into()),
+                    p.target_temperature,
+                    self.ambient_temperature,
+                )
+                .with_run_gas(true),
+            None => builder.with_run_gas(false),
+        }
+        .build();
+        self.profile_duration = self.drv.get_profile_dur(&settings.0)?;
+        self.drv.set_sensor_settings(&mut self.delay, settings)?;
+        Ok(())
     }
 }
 
+/// The sensor's operating range, 300..=1100 hPa
+const PRESSURE_RANGE: RangeInclusive<u32> = 30_000..=110_000;
+
 impl From<FieldData> for Measurement {
     fn from(value: FieldData) -> Self {
+        let pressure = (value.pressure_hpa() * 100.0) as u32;
         Measurement {
             temperature: (value.temperature_celsius() * 100.0) as i32,
             humidity: (value.humidity_percent() * 100.0) as u16,
+            pressure,
+            pressure_valid: PRESSURE_RANGE.contains(&pressure),
+            gas_resistance: value.gas_resistance_ohm(),
+            gas_valid: value.gas_valid() && value.heat_stable(),
+            heater_stable: value.heat_stable(),
         }
     }
 }
@@ -78,8 +236,22 @@ impl fmt::Display for Measurement {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         write!(
             f,
-            "BME680 temperature: {}, humidity: {}",
-            self.temperature, self.humidity
+            "BME680 temperature: {}, humidity: {}, pressure: {}{}, gas resistance: {}{}{}",
+            self.temperature,
+            self.humidity,
+            self.pressure,
+            if self.pressure_valid {
+                ""
+            } else {
+                " (invalid)"
+            },
+            self.gas_resistance,
+            if self.gas_valid { "" } else { " (invalid)" },
+            if self.heater_stable {
+                ""
+            } else {
+                ", heater unstable"
+            },
         )
     }
 }
@@ -0,0 +1,102 @@
+//! Per-unit temperature/humidity calibration.
+//!
+//! The sensor reads the board temperature, which runs warmer than the ambient
+//! air (self-heating, the ENC28J60 next to it). The relative humidity is
+//! relative to that warmer temperature, so after correcting the temperature
+//! it's re-computed from the vapor pressure before its own correction.
+
+use byteorder::{ByteOrder, LittleEndian};
+use core::fmt;
+
+/// Linear correction of a channel, `corrected = raw * gain + offset`
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct Channel {
+    /// Offset in centi-units of the channel
+    pub offset: i32,
+    pub gain: f32,
+}
+
+impl Channel {
+    pub const IDENTITY: Self = Channel {
+        offset: 0,
+        gain: 1.0,
+    };
+
+    fn apply(&self, raw: f32) -> f32 {
+        raw * self.gain + self.offset as f32
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct Calibration {
+    /// Offset in centidegrees C
+    pub temperature: Channel,
+    /// Offset in centipercent
+    pub humidity: Channel,
+}
+
+impl Calibration {
+    pub const ENCODED_LEN: usize = 16;
+
+    /// Correct a measurement's temperature (centidegrees C) and relative humidity (centipercent)
+    pub fn apply(&self, temperature: &mut i32, humidity: &mut u16) {
+        let raw_temp = *temperature as f32;
+        let temp = self.temperature.apply(raw_temp);
+
+        // Same water vapor pressure, relative to the saturation pressure at the corrected temperature
+        let rh = f32::from(*humidity) * saturation_pressure_ratio(raw_temp / 100.0, temp / 100.0);
+        let rh = self.humidity.apply(rh).clamp(0.0, 10_000.0);
+
+        *temperature = temp as i32;
+        *humidity = rh as u16;
+    }
+
+    pub fn emit(&self, buf: &mut [u8]) {
+        LittleEndian::write_i32(&mut buf[0..4], self.temperature.offset);
+        LittleEndian::write_f32(&mut buf[4..8], self.temperature.gain);
+        LittleEndian::write_i32(&mut buf[8..12], self.humidity.offset);
+        LittleEndian::write_f32(&mut buf[12..16], self.humidity.gain);
+    }
+
+    /// Returns `None` if the buffer is too short or a gain isn't a usable number
+    pub fn parse(buf: &[u8]) -> Option<Self> {
+        if buf.len() < Self::ENCODED_LEN {
+            return None;
+        }
+        let cal = Calibration {
+            temperature: Channel {
+                offset: LittleEndian::read_i32(&buf[0..4]),
+                gain: LittleEndian::read_f32(&buf[4..8]),
+            },
+            humidity: Channel {
+                offset: LittleEndian::read_i32(&buf[8..12]),
+                gain: LittleEndian::read_f32(&buf[12..16]),
+            },
+        };
+        if cal.temperature.gain.is_normal() && cal.humidity.gain.is_normal() {
+            Some(cal)
+        } else {
+            None
+        }
+    }
+}
+
+impl fmt::Display for Calibration {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "temperature: offset {} cC, gain {}, humidity: offset {} c%, gain {}",
+            self.temperature.offset,
+            self.temperature.gain,
+            self.humidity.offset,
+            self.humidity.gain
+        )
+    }
+}
+
+/// Ratio of the saturation vapor pressures `es(from) / es(to)`, Magnus formula, degrees C
+fn saturation_pressure_ratio(from: f32, to: f32) -> f32 {
+    const B: f32 = 17.62;
+    const C: f32 = 243.12;
+    libm::expf(B * from / (C + from) - B * to / (C + to))
+}
@@ -0,0 +1,98 @@
+use crate::{config, sensors::bme680::DefaultI2c};
+use core::cell::RefCell;
+use cortex_m::asm;
+use stm32f4xx_hal::{
+    hal::blocking::i2c::{Read, Write},
+    i2c::Error,
+    prelude::*,
+    rcc::Clocks,
+};
+
+pub type I2cBusCell = RefCell<Option<DefaultI2c>>;
+
+/// Handle to the shared I2C2 bus given to the sensor driver, so the bus
+/// can be recovered while the driver still holds on to it
+pub struct I2cProxy(&'static I2cBusCell);
+
+impl Write for I2cProxy {
+    type Error = Error;
+
+    fn write(&mut self, addr: u8, bytes: &[u8]) -> Result<(), Self::Error> {
+        match self.0.borrow_mut().as_mut() {
+            Some(i2c) => i2c.write(addr, bytes),
+            None => Err(Error::Bus),
+        }
+    }
+}
+
+impl Read for I2cProxy {
+    type Error = Error;
+
+    fn read(&mut self, addr: u8, buffer: &mut [u8]) -> Result<(), Self::Error> {
+        match self.0.borrow_mut().as_mut() {
+            Some(i2c) => i2c.read(addr, buffer),
+            None => Err(Error::Bus),
+        }
+    }
+}
+
+/// Owner of the I2C2 bus (SCL on PB10, SDA on PB3)
+pub struct I2cBus {
+    bus: &'static I2cBusCell,
+    clocks: Clocks,
+}
+
+impl I2cBus {
+    pub fn new(bus: &'static I2cBusCell, i2c: DefaultI2c, clocks: Clocks) -> Self {
+        bus.borrow_mut().replace(i2c);
+        I2cBus { bus, clocks }
+    }
+
+    pub fn proxy(&self) -> I2cProxy {
+        I2cProxy(self.bus)
+    }
+
+    /// Release the peripheral, clock out a slave holding SDA low,
+    /// issue a STOP condition and re-initialize the peripheral
+    pub fn recover(&mut self) {
+        let Some(i2c) = self.bus.borrow_mut().take() else {
+            return;
+        };
+        let (i2c2, (scl, sda)) = i2c.release();
+
+        // ~5 us, half of a 100 kHz clock period
+        let half_period = self.clocks.sysclk().raw() / 200_000;
+
+        let mut scl = scl.into_open_drain_output();
+        let mut sda = sda.into_open_drain_output();
+        sda.set_high();
+        scl.set_high();
+        asm::delay(half_period);
+
+        // Up to 9 clocks until the slave releases SDA
+        for _ in 0..9 {
+            if sda.is_high() {
+                break;
+            }
+            scl.set_low();
+            asm::delay(half_period);
+            scl.set_high();
+            asm::delay(half_period);
+        }
+
+        // STOP, SDA rising while SCL is high
+        scl.set_low();
+        asm::delay(half_period);
+        sda.set_low();
+        asm::delay(half_period);
+        scl.set_high();
+        asm::delay(half_period);
+        sda.set_high();
+        asm::delay(half_period);
+
+        let scl = scl.into_alternate().set_open_drain();
+        let sda = sda.into_alternate().set_open_drain();
+        let i2c = i2c2.i2c((scl, sda), config::BME680_I2C_FREQ_KHZ.kHz(), &self.clocks);
+        self.bus.borrow_mut().replace(i2c);
+    }
+}
@@ -0,0 +1,277 @@
+//! Indoor air quality (IAQ) index estimation from the BME680 gas resistance
+//!
+//! This is a simplified, open take on what the Bosch BSEC library provides.
+//! A baseline of the humidity compensated gas resistance is tracked over time,
+//! clean air having the highest resistance. The IAQ index is then derived
+//! from how far the current reading is from the baseline, combined with
+//! how far the relative humidity is from the optimal indoor value.
+//!
+//! The index follows the BSEC scale, 0..=500 where lower is better.
+//!
+//! Nothing in here depends on the HAL so it can be exercised on the host.
+
+use core::fmt;
+
+/// Estimator accuracy, matches the BSEC 0..=3 levels
+#[derive(Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Debug)]
+#[repr(u8)]
+pub enum Accuracy {
+    /// Sensor is burning in, no baseline yet
+    Unreliable = 0,
+    /// Baseline is being established
+    Low = 1,
+    /// Baseline has settled
+    Medium = 2,
+    /// Baseline has been tracked long enough to be trusted
+    High = 3,
+}
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct Estimate {
+    /// IAQ index, 0..=500
+    pub iaq: u16,
+    pub accuracy: Accuracy,
+    /// The humidity compensated gas resistance in ohms
+    pub compensated_gas_resistance: u32,
+}
+
+impl Estimate {
+    /// True once the estimator is calibrated enough to publish the index
+    pub fn is_calibrated(&self) -> bool {
+        self.accuracy >= Accuracy::Medium
+    }
+}
+
+pub struct IaqEstimator {
+    /// Number of samples the estimator has been fed
+    samples: u32,
+    /// Sample thresholds for each accuracy level
+    low_accuracy_samples: u32,
+    medium_accuracy_samples: u32,
+    high_accuracy_samples: u32,
+    /// Baseline of the compensated gas resistance, ohms
+    gas_baseline: f32,
+}
+
+impl IaqEstimator {
+    /// Sensor burn-in period before a baseline is tracked
+    const BURN_IN_SEC: u32 = 5 * 60;
+    const MEDIUM_ACCURACY_SEC: u32 = 30 * 60;
+    const HIGH_ACCURACY_SEC: u32 = 4 * 60 * 60;
+
+    /// Optimal indoor relative humidity, percent
+    const HUMIDITY_REFERENCE: f32 = 40.0;
+    /// Relative change in gas resistance per percent relative humidity
+    const HUMIDITY_COMPENSATION: f32 = 0.017;
+
+    /// Weight of the humidity contribution to the air quality score, the
+    /// remainder is the gas contribution
+    const HUMIDITY_WEIGHT: f32 = 0.25;
+
+    /// Baseline filter coefficients, the baseline rises quickly when the
+    /// air gets cleaner and decays slowly otherwise
+    const BASELINE_RISE_ALPHA: f32 = 0.1;
+    const BASELINE_DECAY_ALPHA: f32 = 0.0005;
+
+    pub const fn new(sample_interval_ms: u32) -> Self {
+        let interval_ms = if sample_interval_ms == 0 {
+            1
+        } else {
+            sample_interval_ms
+        };
+        Self {
+            samples: 0,
+            low_accuracy_samples: Self::BURN_IN_SEC * 1000 / interval_ms,
+            medium_accuracy_samples: Self::MEDIUM_ACCURACY_SEC * 1000 / interval_ms,
+            high_accuracy_samples: Self::HIGH_ACCURACY_SEC * 1000 / interval_ms,
+            gas_baseline: 0.0,
+        }
+    }
+
+    pub fn accuracy(&self) -> Accuracy {
+        if self.samples >= self.high_accuracy_samples {
+            Accuracy::High
+        } else if self.samples >= self.medium_accuracy_samples {
+            Accuracy::Medium
+        } else if self.samples >= self.low_accuracy_samples {
+            Accuracy::Low
+        } else {
+            Accuracy::Unreliable
+        }
+    }
+
+    /// Feed a new gas measurement.
+    ///
+    /// `gas_resistance` is in ohms, `humidity` is in centipercent.
+    pub fn update(&mut self, gas_resistance: u32, humidity: u16) -> Estimate {
+        let rh = f32::from(humidity) / 100.0;
+        let compensation =
+            (1.0 + Self::HUMIDITY_COMPENSATION * (rh - Self::HUMIDITY_REFERENCE)).clamp(0.5, 2.0);
+        let gas = gas_resistance as f32 * compensation;
+
+        self.samples = self.samples.saturating_add(1);
+
+        // During burn-in the baseline just follows the readings
+        let alpha = if self.samples <= self.low_accuracy_samples {
+            1.0
+        } else if gas > self.gas_baseline {
+            Self::BASELINE_RISE_ALPHA
+        } else {
+            Self::BASELINE_DECAY_ALPHA
+        };
+        self.gas_baseline += (gas - self.gas_baseline) * alpha;
+
+        // Humidity score, 1.0 at the reference, 0.0 at 0% or 100%
+        let hum_score = if rh < Self::HUMIDITY_REFERENCE {
+            rh / Self::HUMIDITY_REFERENCE
+        } else {
+            (100.0 - rh) / (100.0 - Self::HUMIDITY_REFERENCE)
+        }
+        .clamp(0.0, 1.0);
+
+        // Gas score, 1.0 at or above the baseline
+        let gas_score = if self.gas_baseline > 0.0 {
+            (gas / self.gas_baseline).clamp(0.0, 1.0)
+        } else {
+            1.0
+        };
+
+        let score = hum_score * Self::HUMIDITY_WEIGHT + gas_score * (1.0 - Self::HUMIDITY_WEIGHT);
+        let iaq = ((1.0 - score) * 500.0).clamp(0.0, 500.0);
+
+        Estimate {
+            iaq: iaq as u16,
+            accuracy: self.accuracy(),
+            compensated_gas_resistance: gas as u32,
+        }
+    }
+}
+
+impl fmt::Display for Estimate {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "IAQ: {}, accuracy: {}, compensated gas resistance: {}",
+            self.iaq, self.accuracy as u8, self.compensated_gas_resistance
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// One sample a minute, burn-in ends after 5 samples, medium accuracy
+    /// after 30, high after 240
+    const INTERVAL_MS: u32 = 60 * 1000;
+
+    /// Relative humidity, in centipercent, where no compensation is applied
+    const REFERENCE_HUMIDITY: u16 = 4000;
+
+    fn burned_in(gas_resistance: u32) -> IaqEstimator {
+        let mut iaq = IaqEstimator::new(INTERVAL_MS);
+        for _ in 0..5 {
+            iaq.update(gas_resistance, REFERENCE_HUMIDITY);
+        }
+        iaq
+    }
+
+    #[test]
+    fn burn_in_follows_the_readings() {
+        let mut iaq = IaqEstimator::new(INTERVAL_MS);
+        for gas in [50_000, 80_000, 20_000, 100_000] {
+            let e = iaq.update(gas, REFERENCE_HUMIDITY);
+            assert_eq!(e.accuracy, Accuracy::Unreliable);
+            assert_eq!(e.compensated_gas_resistance, gas);
+            assert_eq!(e.iaq, 0);
+        }
+        let e = iaq.update(40_000, REFERENCE_HUMIDITY);
+        assert_eq!(e.accuracy, Accuracy::Low);
+        assert_eq!(e.iaq, 0);
+    }
+
+    #[test]
+    fn accuracy_transitions() {
+        let mut iaq = IaqEstimator::new(INTERVAL_MS);
+        assert_eq!(iaq.accuracy(), Accuracy::Unreliable);
+        for sample in 1..=240 {
+            let e = iaq.update(100_000, REFERENCE_HUMIDITY);
+            let expected = match sample {
+                1..=4 => Accuracy::Unreliable,
+                5..=29 => Accuracy::Low,
+                30..=239 => Accuracy::Medium,
+                _ => Accuracy::High,
+            };
+            assert_eq!(e.accuracy, expected, "sample {sample}");
+            assert_eq!(iaq.accuracy(), expected);
+        }
+    }
+
+    #[test]
+    fn only_calibrated_from_medium_accuracy() {
+        let mut iaq = IaqEstimator::new(INTERVAL_MS);
+        for sample in 1..=30 {
+            let e = iaq.update(100_000, REFERENCE_HUMIDITY);
+            assert_eq!(e.is_calibrated(), sample >= 30, "sample {sample}");
+        }
+        for _ in 0..300 {
+            assert!(iaq.update(100_000, REFERENCE_HUMIDITY).is_calibrated());
+        }
+    }
+
+    #[test]
+    fn baseline_decays_slowly() {
+        let mut iaq = burned_in(100_000);
+        // Half the baseline, the gas score is ~0.5 and the humidity score 1.0
+        let e = iaq.update(50_000, REFERENCE_HUMIDITY);
+        assert_eq!(e.iaq, 187);
+        // The baseline barely moved, the index is still close
+        for _ in 0..10 {
+            iaq.update(50_000, REFERENCE_HUMIDITY);
+        }
+        let e = iaq.update(50_000, REFERENCE_HUMIDITY);
+        assert!((185..=187).contains(&e.iaq), "{e:?}");
+    }
+
+    #[test]
+    fn baseline_rises_quickly() {
+        let mut iaq = burned_in(100_000);
+        // Cleaner air than the baseline
+        assert_eq!(iaq.update(200_000, REFERENCE_HUMIDITY).iaq, 0);
+        // The baseline moved 10% of the way to 200k, i.e. 110k
+        let e = iaq.update(55_000, REFERENCE_HUMIDITY);
+        assert_eq!(e.iaq, 187);
+    }
+
+    #[test]
+    fn humidity_compensation() {
+        let mut iaq = IaqEstimator::new(INTERVAL_MS);
+        assert_eq!(
+            iaq.update(100_000, REFERENCE_HUMIDITY)
+                .compensated_gas_resistance,
+            100_000
+        );
+        // 1.7% per percent above the reference
+        let gas = iaq.update(100_000, 5000).compensated_gas_resistance;
+        assert!((116_900..=117_100).contains(&gas), "{gas}");
+        // And below it
+        let gas = iaq.update(100_000, 3000).compensated_gas_resistance;
+        assert!((82_900..=83_100).contains(&gas), "{gas}");
+        // Limited to 2x
+        let gas = iaq.update(100_000, 10_000).compensated_gas_resistance;
+        assert_eq!(gas, 200_000);
+    }
+
+    #[test]
+    fn humidity_away_from_the_reference_raises_the_index() {
+        let mut iaq = burned_in(100_000);
+        // Dry air with the same compensated gas resistance as the baseline,
+        // only the humidity score contributes
+        let e = iaq.update(100_000 * 100 / 66, 2000);
+        assert!((60..=63).contains(&e.iaq), "{e:?}");
+        // 0% or 100% RH scores 0, the humidity weight is 25%
+        let mut iaq = burned_in(100_000);
+        let e = iaq.update(50_000, 10_000);
+        assert_eq!(e.iaq, 125);
+    }
+}
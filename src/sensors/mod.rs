@@ -1,3 +1,7 @@
 pub mod bme680;
+pub mod i2c_bus;
+
+pub use bme680_env_monitor::sensors::{calibration, iaq};
 
 pub use self::bme680::Bme680;
+pub use self::i2c_bus::{I2cBus, I2cBusCell, I2cProxy};
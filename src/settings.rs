@@ -0,0 +1,328 @@
+//! Runtime configuration.
+//!
+//! Stored in the flash store as a versioned record, the compiled defaults
+//! (`config::DEFAULT_SETTINGS`) are used when nothing valid is stored.
+//! Changes to the MAC address, IP mode, static address and gateway take effect
+//! after a restart, the others right away.
+//!
+//! Record layout, version 1, little endian:
+//!
+//! | Offset | Len | Field                        |
+//! |--------|-----|------------------------------|
+//! | 0      | 1   | version                      |
+//! | 1      | 2   | device ID                    |
+//! | 3      | 6   | MAC address                  |
+//! | 9      | 1   | IP config mode, 0 static     |
+//! | 10     | 4   | static IP address            |
+//! | 14     | 1   | static IP prefix length      |
+//! | 15     | 4   | static gateway               |
+//! | 19     | 4   | broadcast address            |
+//! | 23     | 2   | broadcast port               |
+//! | 25     | 4   | broadcast interval, seconds  |
+//! | 29     | 4   | SNTP server address          |
+//! | 33     | 4   | SNTP sync interval, seconds  |
+//! | 37     | 16  | calibration                  |
+//! | 53     | 4   | syslog collector address     |
+//! | 57     | 2   | syslog collector port        |
+//! | 59     | 1   | syslog log level             |
+//! | 60     | 1   | default log level            |
+//! | 61     | 1   | module log level count, N    |
+//! | 62     | 2N  | module index and log level   |
+//!
+//! The module index is the position in `log_levels::MODULES`, only the modules
+//! with a level of their own are stored.
+
+use crate::{
+    log_levels::{self, LogLevels},
+    sensors::calibration::Calibration,
+};
+use byteorder::{ByteOrder, LittleEndian};
+use core::fmt;
+use smoltcp::wire::{EthernetAddress, Ipv4Address, Ipv4Cidr};
+
+/// How the interface gets its IPv4 address
+#[derive(Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Debug)]
+pub enum IpConfigMode {
+    /// The static address from the settings
+    Static,
+    /// DHCP, falling back to the static address if no lease is obtained in time
+    Dhcp,
+}
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct Settings {
+    pub device_id: u16,
+    pub mac_address: [u8; 6],
+    pub ip_config_mode: IpConfigMode,
+    /// Used in static mode, and as the DHCP fallback
+    pub static_ip_cidr: Ipv4Cidr,
+    pub static_gateway: Ipv4Address,
+    /// Used when the address isn't leased
+    pub broadcast_address: Ipv4Address,
+    pub broadcast_port: u16,
+    pub bcast_interval_sec: u32,
+    pub sntp_server_address: Ipv4Address,
+    pub sntp_sync_interval_sec: u32,
+    pub calibration: Calibration,
+    pub log_levels: LogLevels,
+    /// Unspecified when there's no collector
+    pub syslog_address: Ipv4Address,
+    pub syslog_port: u16,
+}
+
+impl Settings {
+    pub const VERSION: u8 = 1;
+
+    /// Offset of the module log levels, the record is variable length from here
+    const MODULE_LEVELS_OFFSET: usize = 37 + Calibration::ENCODED_LEN + 4 + 2 + 1 + 1 + 1;
+
+    /// Length of the largest record, with a level for every module
+    pub const MAX_ENCODED_LEN: usize = Self::MODULE_LEVELS_OFFSET + 2 * log_levels::MODULES.len();
+
+    /// Upper bound of the broadcast interval
+    pub const MAX_BCAST_INTERVAL_SEC: u32 = 60;
+
+    /// Log records are queued for the syslog collector once there is one
+    pub fn syslog_collector(&self) -> bool {
+        !self.syslog_address.is_unspecified()
+    }
+
+    /// Destination port of the diagnostic messages
+    pub fn diagnostics_port(&self) -> u16 {
+        self.broadcast_port.wrapping_add(1)
+    }
+
+    /// Text command port, see `command.rs`
+    pub fn command_port(&self) -> u16 {
+        self.broadcast_port.wrapping_add(2)
+    }
+
+    pub fn bcast_interval_valid(&self) -> bool {
+        (1..=Self::MAX_BCAST_INTERVAL_SEC).contains(&self.bcast_interval_sec)
+    }
+
+    /// Returns the length of the record
+    pub fn emit(&self, buf: &mut [u8]) -> usize {
+        buf[0] = Self::VERSION;
+        LittleEndian::write_u16(&mut buf[1..3], self.device_id);
+        buf[3..9].copy_from_slice(&self.mac_address);
+        buf[9] = match self.ip_config_mode {
+            IpConfigMode::Static => 0,
+            IpConfigMode::Dhcp => 1,
+        };
+        buf[10..14].copy_from_slice(self.static_ip_cidr.address().as_bytes());
+        buf[14] = self.static_ip_cidr.prefix_len();
+        buf[15..19].copy_from_slice(self.static_gateway.as_bytes());
+        buf[19..23].copy_from_slice(self.broadcast_address.as_bytes());
+        LittleEndian::write_u16(&mut buf[23..25], self.broadcast_port);
+        LittleEndian::write_u32(&mut buf[25..29], self.bcast_interval_sec);
+        buf[29..33].copy_from_slice(self.sntp_server_address.as_bytes());
+        LittleEndian::write_u32(&mut buf[33..37], self.sntp_sync_interval_sec);
+        self.calibration.emit(&mut buf[37..]);
+        buf[53..57].copy_from_slice(self.syslog_address.as_bytes());
+        LittleEndian::write_u16(&mut buf[57..59], self.syslog_port);
+        buf[59] = self.log_levels.syslog as u8;
+        buf[60] = self.log_levels.default as u8;
+        let mut len = Self::MODULE_LEVELS_OFFSET;
+        for (idx, level) in self.log_levels.modules.iter().enumerate() {
+            if let Some(level) = level {
+                buf[len] = idx as u8;
+                buf[len + 1] = *level as u8;
+                len += 2;
+            }
+        }
+        buf[61] = ((len - Self::MODULE_LEVELS_OFFSET) / 2) as u8;
+        len
+    }
+
+    /// Returns `None` for unknown versions or invalid fields
+    pub fn parse(buf: &[u8]) -> Option<Self> {
+        if buf.len() < Self::MODULE_LEVELS_OFFSET || buf[0] != Self::VERSION {
+            return None;
+        }
+        let ip_config_mode = match buf[9] {
+            0 => IpConfigMode::Static,
+            1 => IpConfigMode::Dhcp,
+            _ => return None,
+        };
+        let prefix_len = buf[14];
+        if prefix_len > 32 {
+            return None;
+        }
+        let mut mac_address = [0; 6];
+        mac_address.copy_from_slice(&buf[3..9]);
+        let settings = Settings {
+            device_id: LittleEndian::read_u16(&buf[1..3]),
+            mac_address,
+            ip_config_mode,
+            static_ip_cidr: Ipv4Cidr::new(Ipv4Address::from_bytes(&buf[10..14]), prefix_len),
+            static_gateway: Ipv4Address::from_bytes(&buf[15..19]),
+            broadcast_address: Ipv4Address::from_bytes(&buf[19..23]),
+            broadcast_port: LittleEndian::read_u16(&buf[23..25]),
+            bcast_interval_sec: LittleEndian::read_u32(&buf[25..29]),
+            sntp_server_address: Ipv4Address::from_bytes(&buf[29..33]),
+            sntp_sync_interval_sec: LittleEndian::read_u32(&buf[33..37]),
+            calibration: Calibration::parse(&buf[37..])?,
+            log_levels: parse_log_levels(&buf[59..])?,
+            syslog_address: Ipv4Address::from_bytes(&buf[53..57]),
+            syslog_port: LittleEndian::read_u16(&buf[57..59]),
+        };
+        if !settings.bcast_interval_valid()
+            || settings.sntp_sync_interval_sec == 0
+            || !EthernetAddress(mac_address).is_unicast()
+        {
+            return None;
+        }
+        Some(settings)
+    }
+}
+
+impl fmt::Display for Settings {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "device_id: 0x{:X} ({})", self.device_id, self.device_id)?;
+        writeln!(f, "mac: {}", EthernetAddress(self.mac_address))?;
+        writeln!(f, "ip_mode: {:?}", self.ip_config_mode)?;
+        writeln!(f, "ip: {}", self.static_ip_cidr)?;
+        writeln!(f, "gateway: {}", self.static_gateway)?;
+        writeln!(f, "bcast_addr: {}", self.broadcast_address)?;
+        writeln!(f, "bcast_port: {}", self.broadcast_port)?;
+        writeln!(f, "bcast_interval: {}", self.bcast_interval_sec)?;
+        writeln!(f, "sntp_server: {}", self.sntp_server_address)?;
+        writeln!(f, "sntp_interval: {}", self.sntp_sync_interval_sec)?;
+        writeln!(f, "calibration: {}", self.calibration)?;
+        writeln!(f, "syslog_addr: {}", self.syslog_address)?;
+        writeln!(f, "syslog_port: {}", self.syslog_port)?;
+        write!(f, "log: {}", self.log_levels)
+    }
+}
+
+/// The syslog level onwards
+fn parse_log_levels(buf: &[u8]) -> Option<LogLevels> {
+    let mut levels = LogLevels {
+        default: log_levels::level_filter_from_u8(buf[1])?,
+        modules: [None; log_levels::MODULES.len()],
+        syslog: log_levels::level_filter_from_u8(buf[0])?,
+    };
+    let count = usize::from(buf[2]);
+    let pairs = buf.get(3..3 + 2 * count)?;
+    for [idx, level] in pairs.as_chunks::<2>().0 {
+        let level = log_levels::level_filter_from_u8(*level)?;
+        *levels.modules.get_mut(usize::from(*idx))? = Some(level);
+    }
+    Some(levels)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sensors::calibration::Channel;
+    use log::LevelFilter;
+
+    fn settings() -> Settings {
+        Settings {
+            device_id: 0xAB01,
+            mac_address: [0x02, 0x00, 0x04, 0x03, 0x07, 0x02],
+            ip_config_mode: IpConfigMode::Dhcp,
+            static_ip_cidr: Ipv4Cidr::new(Ipv4Address([192, 168, 1, 38]), 24),
+            static_gateway: Ipv4Address([192, 168, 1, 1]),
+            broadcast_address: Ipv4Address([192, 168, 1, 255]),
+            broadcast_port: 32100,
+            bcast_interval_sec: 5,
+            sntp_server_address: Ipv4Address([162, 159, 200, 123]),
+            sntp_sync_interval_sec: 3600,
+            calibration: Calibration {
+                temperature: Channel {
+                    offset: -150,
+                    gain: 1.0,
+                },
+                humidity: Channel {
+                    offset: 200,
+                    gain: 0.98,
+                },
+            },
+            log_levels: LogLevels {
+                default: LevelFilter::Info,
+                modules: [None; log_levels::MODULES.len()],
+                syslog: LevelFilter::Warn,
+            },
+            syslog_address: Ipv4Address([192, 168, 1, 10]),
+            syslog_port: 514,
+        }
+    }
+
+    fn emit(settings: &Settings) -> ([u8; Settings::MAX_ENCODED_LEN], usize) {
+        let mut buf = [0; Settings::MAX_ENCODED_LEN];
+        let len = settings.emit(&mut buf);
+        (buf, len)
+    }
+
+    #[test]
+    fn round_trip() {
+        let mut s = settings();
+        s.log_levels.modules[3] = Some(LevelFilter::Debug);
+        let (buf, len) = emit(&s);
+        assert_eq!(len, Settings::MODULE_LEVELS_OFFSET + 2);
+        assert_eq!(Settings::parse(&buf[..len]), Some(s));
+    }
+
+    #[test]
+    fn round_trip_all_modules() {
+        let mut s = settings();
+        s.ip_config_mode = IpConfigMode::Static;
+        s.log_levels.modules = [Some(LevelFilter::Trace); log_levels::MODULES.len()];
+        s.log_levels.modules[0] = Some(LevelFilter::Off);
+        let (buf, len) = emit(&s);
+        assert_eq!(len, Settings::MAX_ENCODED_LEN);
+        assert_eq!(Settings::parse(&buf[..len]), Some(s));
+    }
+
+    #[test]
+    fn rejects_invalid_fields() {
+        let (valid, len) = emit(&settings());
+        let parse_with = |offset: usize, value: &[u8]| {
+            let mut buf = valid;
+            buf[offset..offset + value.len()].copy_from_slice(value);
+            Settings::parse(&buf[..len])
+        };
+        assert!(parse_with(0, &[0]).is_none(), "version");
+        assert!(parse_with(0, &[Settings::VERSION + 1]).is_none(), "version");
+        assert!(parse_with(9, &[2]).is_none(), "IP config mode");
+        assert!(parse_with(14, &[33]).is_none(), "prefix length");
+        assert!(parse_with(25, &[0; 4]).is_none(), "broadcast interval");
+        assert!(
+            parse_with(25, &[Settings::MAX_BCAST_INTERVAL_SEC as u8 + 1]).is_none(),
+            "broadcast interval"
+        );
+        assert!(parse_with(33, &[0; 4]).is_none(), "SNTP interval");
+        assert!(parse_with(3, &[0x03]).is_none(), "multicast MAC");
+        assert!(parse_with(59, &[6]).is_none(), "syslog level");
+        assert!(parse_with(60, &[6]).is_none(), "default level");
+        assert!(Settings::parse(&valid[..len]).is_some());
+    }
+
+    #[test]
+    fn rejects_invalid_module_levels() {
+        let mut s = settings();
+        s.log_levels.modules[1] = Some(LevelFilter::Debug);
+        s.log_levels.modules[2] = Some(LevelFilter::Error);
+        let (valid, len) = emit(&s);
+
+        let mut buf = valid;
+        buf[Settings::MODULE_LEVELS_OFFSET] = log_levels::MODULES.len() as u8;
+        assert!(Settings::parse(&buf[..len]).is_none(), "module index");
+
+        let mut buf = valid;
+        buf[Settings::MODULE_LEVELS_OFFSET + 1] = 6;
+        assert!(Settings::parse(&buf[..len]).is_none(), "module level");
+
+        assert!(
+            Settings::parse(&valid[..len - 1]).is_none(),
+            "truncated pairs"
+        );
+        assert!(
+            Settings::parse(&valid[..Settings::MODULE_LEVELS_OFFSET - 1]).is_none(),
+            "truncated record"
+        );
+        assert!(Settings::parse(&valid[..len]).is_some());
+    }
+}
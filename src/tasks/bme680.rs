@@ -1,16 +1,182 @@
 use crate::{
     app::{bme680_task, data_manager_task},
     config,
-    tasks::data_manager::SpawnArg as DataManagerSpawnArg,
+    logging::{debug, info, warn, Debug2Format, Display2Format},
+    sensors::{
+        bme680::{Fault, HeaterProfile},
+        Bme680, I2cBus, I2cProxy,
+    },
+    tasks::{data_manager::SpawnArg as DataManagerSpawnArg, heartbeat, SupervisedTask},
 };
-use log::debug;
-use stm32f4xx_hal::prelude::*;
+use core::fmt;
+use stm32f4xx_hal::{pac::TIM10, prelude::*, timer::DelayMs};
 
-pub(crate) fn bme680_task(ctx: bme680_task::Context) {
+#[derive(Copy, Clone, Eq, PartialEq, Hash, Debug)]
+pub enum SpawnArg {
+    /// Time to measure, reschedules itself
+    Measure,
+    /// Recover the I2C bus and re-initialize the sensor, requested by a command
+    Reinit,
+    /// Change the gas heater profile, requested by a command
+    SetHeater(Option<HeaterProfile>),
+}
+
+pub struct TaskState {
+    consecutive_failures: u32,
+    counters: FaultCounters,
+}
+
+impl TaskState {
+    pub const fn new() -> Self {
+        Self {
+            consecutive_failures: 0,
+            counters: FaultCounters::new(),
+        }
+    }
+}
+
+/// Total number of measurement failures, by class
+struct FaultCounters {
+    i2c_nack: u32,
+    i2c_arbitration_loss: u32,
+    i2c_bus: u32,
+    i2c_timeout: u32,
+    i2c_other: u32,
+    driver: u32,
+    bus_recoveries: u32,
+}
+
+impl FaultCounters {
+    const fn new() -> Self {
+        Self {
+            i2c_nack: 0,
+            i2c_arbitration_loss: 0,
+            i2c_bus: 0,
+            i2c_timeout: 0,
+            i2c_other: 0,
+            driver: 0,
+            bus_recoveries: 0,
+        }
+    }
+
+    fn record(&mut self, fault: Fault) {
+        let c = match fault {
+            Fault::Nack => &mut self.i2c_nack,
+            Fault::ArbitrationLoss => &mut self.i2c_arbitration_loss,
+            Fault::Bus => &mut self.i2c_bus,
+            Fault::Timeout => &mut self.i2c_timeout,
+            Fault::I2c => &mut self.i2c_other,
+            Fault::DeviceNotFound | Fault::NoNewData | Fault::Driver => &mut self.driver,
+        };
+        *c = c.wrapping_add(1);
+    }
+}
+
+impl fmt::Display for FaultCounters {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "nack: {}, arbitration loss: {}, bus: {}, timeout: {}, other i2c: {}, driver: {}, bus recoveries: {}",
+            self.i2c_nack,
+            self.i2c_arbitration_loss,
+            self.i2c_bus,
+            self.i2c_timeout,
+            self.i2c_other,
+            self.driver,
+            self.bus_recoveries
+        )
+    }
+}
+
+pub(crate) fn bme680_task(ctx: bme680_task::Context, arg: SpawnArg) {
     let sensor = ctx.local.bme680;
-    let measurement = sensor.measure().unwrap();
-    debug!("{measurement}");
+    let i2c_bus = ctx.local.i2c_bus;
+    let state = ctx.local.state;
+    let settings = ctx.shared.settings;
+    let last_measurement = ctx.shared.last_measurement;
+
+    match arg {
+        SpawnArg::Measure => (),
+        SpawnArg::Reinit => {
+            info!("BME680: re-initializing");
+            recover(sensor, i2c_bus, state);
+            return;
+        }
+        SpawnArg::SetHeater(profile) => {
+            match sensor.set_heater_profile(profile) {
+                Ok(()) => info!("BME680: heater profile {:?}", Debug2Format(&profile)),
+                Err(e) => warn!(
+                    "BME680: failed to set the heater profile. {:?}",
+                    Debug2Format(&e)
+                ),
+            }
+            return;
+        }
+    }
+
+    heartbeat(SupervisedTask::Bme680);
+
+    let next_measurement_ms = match sensor.measure() {
+        Ok(mut measurement) => {
+            if state.consecutive_failures != 0 {
+                info!(
+                    "BME680: recovered after {} failures",
+                    state.consecutive_failures
+                );
+                state.consecutive_failures = 0;
+            }
+            settings
+                .calibration
+                .apply(&mut measurement.temperature, &mut measurement.humidity);
+            debug!("{}", Display2Format(&measurement));
+            *last_measurement = Some(measurement);
+
+            data_manager_task::spawn(DataManagerSpawnArg::Bme680Measurement(measurement)).unwrap();
+            config::BME680_MEASUREMENT_INTERVAL_MS
+        }
+        Err(e) => {
+            let fault = Fault::from_error(&e);
+            state.counters.record(fault);
+            state.consecutive_failures = state.consecutive_failures.saturating_add(1);
+            warn!(
+                "BME680: measurement failed, {} consecutive. {:?}",
+                state.consecutive_failures,
+                Debug2Format(&e)
+            );
+
+            if state.consecutive_failures % config::BME680_FAULT_THRESHOLD == 0 {
+                if state.consecutive_failures == config::BME680_FAULT_THRESHOLD {
+                    data_manager_task::spawn(DataManagerSpawnArg::Bme680Fault(fault)).ok();
+                }
+
+                warn!("BME680: recovering the I2C bus");
+                recover(sensor, i2c_bus, state);
+                warn!("BME680: fault counters {}", Display2Format(&state.counters));
+            }
+
+            retry_interval_ms(state.consecutive_failures)
+        }
+    };
+
+    bme680_task::spawn_after(next_measurement_ms.millis(), SpawnArg::Measure).unwrap();
+}
+
+fn recover(
+    sensor: &mut Bme680<DelayMs<TIM10>, I2cProxy>,
+    i2c_bus: &mut I2cBus,
+    state: &mut TaskState,
+) {
+    state.counters.bus_recoveries = state.counters.bus_recoveries.wrapping_add(1);
+    i2c_bus.recover();
+    if let Err(e) = sensor.reinit(i2c_bus.proxy()) {
+        warn!("BME680: re-initialization failed. {:?}", Debug2Format(&e));
+    }
+}
 
-    data_manager_task::spawn(DataManagerSpawnArg::Bme680Measurement(measurement)).unwrap();
-    bme680_task::spawn_after(config::BME680_MEASUREMENT_INTERVAL_MS.millis()).unwrap();
+/// Exponential backoff of the measurement interval
+fn retry_interval_ms(consecutive_failures: u32) -> u32 {
+    let shift = consecutive_failures.min(16);
+    config::BME680_MEASUREMENT_INTERVAL_MS
+        .saturating_mul(1 << shift)
+        .min(config::BME680_MAX_RETRY_INTERVAL_MS)
 }
@@ -0,0 +1,63 @@
+use crate::{
+    app::command_task,
+    command::{self, Context},
+    config,
+    logging::{warn, Debug2Format},
+    util::TruncatingWriter,
+};
+use core::fmt::Write;
+use smoltcp::socket::udp::Socket as UdpSocket;
+use stm32f4xx_hal::prelude::*;
+
+/// Length of the largest response
+pub const MAX_RESPONSE_LEN: usize = 512;
+
+pub(crate) fn command_task(ctx: command_task::Context) {
+    let sockets = ctx.shared.sockets;
+    let command_socket_handle = ctx.shared.command_socket;
+    let eth = ctx.shared.eth;
+    let settings = ctx.shared.settings;
+    let flash_store = ctx.shared.flash_store;
+    let time = ctx.shared.time;
+    let ip_config = ctx.shared.ip_config;
+    let last_measurement = ctx.shared.last_measurement;
+
+    let socket = sockets.get_mut::<UdpSocket>(*command_socket_handle);
+    if socket.is_open() && socket.endpoint().port != settings.command_port() {
+        // The broadcast port was changed
+        socket.close();
+    }
+    if !socket.is_open() {
+        socket.bind(settings.command_port()).unwrap();
+    }
+
+    if let Ok((buf, endpoint)) = socket.recv() {
+        let line = core::str::from_utf8(&buf[..buf.len().min(command::MAX_LINE_LEN)]);
+        let mut response = [0_u8; MAX_RESPONSE_LEN];
+        let mut w = TruncatingWriter::new(&mut response);
+        match line {
+            Ok(line) => {
+                let mut cmd_ctx = Context {
+                    settings,
+                    flash_store,
+                    time,
+                    ip_config,
+                    link: eth.link(),
+                    eth_errors: eth.error_counters(),
+                    last_measurement: *last_measurement,
+                    allow_write: false,
+                };
+                command::execute(line.trim(), &mut cmd_ctx, &mut w).ok();
+            }
+            Err(_) => {
+                writeln!(w, "error: not UTF-8").ok();
+            }
+        }
+        let len = w.len();
+        if let Err(e) = socket.send_slice(&response[..len], endpoint) {
+            warn!("CMD: failed to send response. {:?}", Debug2Format(&e));
+        }
+    }
+
+    command_task::spawn_after(config::COMMAND_POLL_INTERVAL_MS.millis()).unwrap();
+}
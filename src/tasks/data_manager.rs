@@ -1,6 +1,17 @@
-use crate::{app::data_manager_task, config, sensors::bme680, util};
-use log::{debug, warn};
-use smoltcp::{socket::udp::Socket as UdpSocket, wire::Ipv4Address};
+use crate::{
+    app::data_manager_task,
+    config,
+    field_timestamps::{Field, FieldTimestamps},
+    logging::{debug, info, warn, Debug2Format, Display2Format},
+    net::{
+        diagnostics,
+        stats::{self, STATISTICS},
+    },
+    sensors::{bme680, iaq::IaqEstimator},
+    tasks::{heartbeat, SupervisedTask},
+    util,
+};
+use smoltcp::socket::udp::Socket as UdpSocket;
 use stm32f4xx_hal::prelude::*;
 use wire_protocols::{
     broadcast::{Message as WireMessage, Repr as Message},
@@ -11,15 +22,25 @@ const LOCAL_EPHEMERAL_PORT: u16 = 16000;
 
 #[derive(Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Debug)]
 pub enum SpawnArg {
-    /// Temperature and humidity measurement from the BME680 sensor
+    /// Temperature, humidity, pressure and gas resistance measurement from the BME680 sensor
     Bme680Measurement(bme680::Measurement),
+    /// The BME680 sensor is failing to produce measurements
+    Bme680Fault(bme680::Fault),
     /// Time to send the broadcast protocol data
     SendBroadcastMessage,
+    /// Time to log and send the network statistics, reschedules itself
+    SendNetStatistics,
 }
 
 pub struct TaskState {
     msg: Message,
     cycles_till_warmed_up: u32,
+    iaq: IaqEstimator,
+    timestamps: FieldTimestamps,
+    /// Link downs already reported with a link report
+    link_downs_reported: u32,
+    /// Network statistics not sent yet, retried on the broadcast cycles
+    net_stats: Option<stats::Snapshot>,
 }
 
 impl TaskState {
@@ -27,17 +48,28 @@ impl TaskState {
         Self {
             msg: default_bcast_message(),
             cycles_till_warmed_up: config::DATA_MANAGER_WARM_UP_PERIOD_CYCLES,
+            iaq: IaqEstimator::new(config::BME680_MEASUREMENT_INTERVAL_MS),
+            timestamps: FieldTimestamps::new(),
+            link_downs_reported: 0,
+            net_stats: None,
         }
     }
 }
 
-// TODO - state management, rtc, status bits, timeout/invalidate, etc
-// add a warm up period before starting the broadcast protocol
+// TODO - state management, rtc, status bits, etc
 // make SystemStatus msg sn Option to indicate it on display too
 pub(crate) fn data_manager_task(ctx: data_manager_task::Context, arg: SpawnArg) {
     let state = ctx.local.state;
+    let crash_report = ctx.local.crash_report;
+    let reset_report = ctx.local.reset_report;
+    let eth = ctx.shared.eth;
     let sockets = ctx.shared.sockets;
     let udp_socket_handle = ctx.shared.udp_socket;
+    let time = ctx.shared.time;
+    let ip_config = ctx.shared.ip_config;
+    let settings = ctx.shared.settings;
+
+    heartbeat(SupervisedTask::DataManager);
 
     let socket = sockets.get_mut::<UdpSocket>(*udp_socket_handle);
 
@@ -46,17 +78,70 @@ pub(crate) fn data_manager_task(ctx: data_manager_task::Context, arg: SpawnArg)
         state.msg.device_serial_number = util::read_device_serial_number();
         state.msg.status_flags.set_initialized(true);
     }
+    // Can be changed at runtime
+    state.msg.device_id = settings.device_id;
 
+    let now = time.uptime_seconds();
+    state.msg.uptime_seconds = now;
     let mut send_msg = false;
     match arg {
         SpawnArg::Bme680Measurement(m) => {
+            state.timestamps.update(Field::Temperature, now);
+            state.timestamps.update(Field::Humidity, now);
             state.msg.temperature = m.temperature;
             state.msg.humidity = m.humidity;
             state.msg.status_flags.set_temperature_valid(true);
             state.msg.status_flags.set_humidity_valid(true);
+
+            // Not part of the broadcast protocol (yet)
+            if m.pressure_valid {
+                debug!("DM: pressure {} Pa", m.pressure);
+            }
+
+            if m.gas_valid {
+                let estimate = state.iaq.update(m.gas_resistance, m.humidity);
+                debug!("DM: {}", Display2Format(&estimate));
+
+                // VOC ticks carry the raw gas resistance in units of 10 ohms
+                state.msg.voc_ticks = (m.gas_resistance / 10).min(u16::MAX.into()) as u16;
+                state.msg.status_flags.set_voc_ticks_valid(true);
+                state.timestamps.update(Field::VocTicks, now);
+
+                state.msg.voc_index = estimate.iaq;
+                if estimate.is_calibrated() {
+                    state.msg.status_flags.set_voc_index_valid(true);
+                    state.timestamps.update(Field::VocIndex, now);
+                } else {
+                    state.msg.status_flags.set_voc_index_valid(false);
+                }
+            } else {
+                state.msg.status_flags.set_voc_ticks_valid(false);
+                state.msg.status_flags.set_voc_index_valid(false);
+            }
+        }
+        SpawnArg::Bme680Fault(fault) => {
+            warn!(
+                "DM: BME680 sensor fault {:?}, invalidating its fields",
+                Debug2Format(&fault)
+            );
+            state.timestamps.invalidate_all(&mut state.msg.status_flags);
         }
         SpawnArg::SendBroadcastMessage => {
-            // TODO invalidate stale fields on timer or keep valid?
+            time.sync_from_rtc();
+            match time.utc() {
+                Some(utc) => {
+                    state.msg.datetime = utc.into();
+                    state.msg.status_flags.set_datetime_valid(true);
+                }
+                None => state.msg.status_flags.set_datetime_valid(false),
+            }
+
+            state.timestamps.invalidate_stale(
+                now,
+                config::DATA_MANAGER_MAX_DATA_AGE_SEC,
+                &mut state.msg.status_flags,
+                |field| warn!("DM: {} is stale", field.name()),
+            );
 
             if state.cycles_till_warmed_up != 0 {
                 state.cycles_till_warmed_up = state.cycles_till_warmed_up.saturating_sub(1);
@@ -68,31 +153,118 @@ pub(crate) fn data_manager_task(ctx: data_manager_task::Context, arg: SpawnArg)
                 send_msg = true;
             }
 
-            state.msg.uptime_seconds += config::BCAST_INTERVAL_SEC;
-
             data_manager_task::spawn_after(
-                config::BCAST_INTERVAL_SEC.secs(),
+                settings.bcast_interval_sec.secs(),
                 SpawnArg::SendBroadcastMessage,
             )
             .unwrap();
         }
+        SpawnArg::SendNetStatistics => {
+            let snapshot = STATISTICS.snapshot(&eth.error_counters());
+            info!("DM: network {}", Display2Format(&snapshot));
+            // Replaces one that couldn't be sent, the counters only grow
+            state.net_stats = Some(snapshot);
+
+            data_manager_task::spawn_after(
+                config::NET_STATS_INTERVAL_SEC.secs(),
+                SpawnArg::SendNetStatistics,
+            )
+            .unwrap();
+        }
+    }
+
+    // The diagnostic reports go out once, as soon as possible, one per cycle
+    // in place of the broadcast message
+    // The link report says the link went down since the last one
+    let link = eth.link();
+    let link_report = link.downs != state.link_downs_reported;
+    let send_report = matches!(arg, SpawnArg::SendBroadcastMessage)
+        && (crash_report.is_some() || reset_report.is_some() || link_report);
+    // Sent when taken, or on a later broadcast cycle
+    let send_net_stats = matches!(
+        arg,
+        SpawnArg::SendNetStatistics | SpawnArg::SendBroadcastMessage
+    ) && state.net_stats.is_some();
+
+    // Paused while the link is down, the reports wait for it
+    let link_up = link.up;
+    let broadcast_address = if (send_msg || send_report || send_net_stats) && link_up {
+        ip_config.broadcast_address(settings)
+    } else {
+        None
+    };
+    if send_msg && !link_up {
+        debug!("DM: link down, not sending");
+    } else if send_msg && broadcast_address.is_none() {
+        debug!("DM: no IP address yet, not sending");
     }
 
-    if send_msg {
+    if let Some(broadcast_address) = broadcast_address {
         if !socket.is_open() {
             socket.bind(LOCAL_EPHEMERAL_PORT).unwrap();
         }
 
-        if socket.can_send() {
+        if !socket.can_send() {
+            warn!("Socket cannot send");
+            socket.close();
+        } else if let Some(report) = crash_report.take() {
+            match socket.send(
+                diagnostics::crash_report_len(&report),
+                (broadcast_address, settings.diagnostics_port()).into(),
+            ) {
+                Err(e) => {
+                    warn!("Failed to send crash report. {:?}", Debug2Format(&e));
+                    crash_report.replace(report);
+                }
+                Ok(buf) => {
+                    diagnostics::emit_crash_report(&report, settings.device_id, buf);
+                    info!("DM: Sent crash report");
+                }
+            }
+        } else if let Some(report) = reset_report.take() {
+            match socket.send(
+                diagnostics::RESET_REPORT_LEN,
+                (broadcast_address, settings.diagnostics_port()).into(),
+            ) {
+                Err(e) => {
+                    warn!("Failed to send reset report. {:?}", Debug2Format(&e));
+                    reset_report.replace(report);
+                }
+                Ok(buf) => {
+                    diagnostics::emit_reset_report(&report, settings.device_id, buf);
+                    info!("DM: Sent reset report");
+                }
+            }
+        } else if send_report && link_report {
+            match socket.send(
+                diagnostics::LINK_REPORT_LEN,
+                (broadcast_address, settings.diagnostics_port()).into(),
+            ) {
+                Err(e) => warn!("Failed to send link report. {:?}", Debug2Format(&e)),
+                Ok(buf) => {
+                    diagnostics::emit_link_report(&link, settings.device_id, buf);
+                    state.link_downs_reported = link.downs;
+                    info!("DM: Sent link report, {} downs", link.downs);
+                }
+            }
+        } else if let Some(snapshot) = state.net_stats.filter(|_| send_net_stats) {
+            match socket.send(
+                diagnostics::NET_STATISTICS_LEN,
+                (broadcast_address, settings.diagnostics_port()).into(),
+            ) {
+                Err(e) => warn!("Failed to send network statistics. {:?}", Debug2Format(&e)),
+                Ok(buf) => {
+                    diagnostics::emit_net_statistics(&snapshot, settings.device_id, buf);
+                    state.net_stats = None;
+                    debug!("DM: Sent network statistics");
+                }
+            }
+        } else if send_msg {
             match socket.send(
                 state.msg.message_len(),
-                (
-                    Ipv4Address(config::BROADCAST_ADDRESS),
-                    config::BROADCAST_PORT,
-                )
-                    .into(),
+                (broadcast_address, settings.broadcast_port).into(),
             ) {
-                Err(e) => warn!("Failed to send. {e:?}"),
+                Err(e) => warn!("Failed to send. {:?}", Debug2Format(&e)),
                 Ok(buf) => {
                     let mut wire = WireMessage::new_unchecked(buf);
                     state.msg.emit(&mut wire);
@@ -100,9 +272,6 @@ pub(crate) fn data_manager_task(ctx: data_manager_task::Context, arg: SpawnArg)
                     state.msg.sequence_number = state.msg.sequence_number.wrapping_add(1);
                 }
             }
-        } else {
-            warn!("Socket cannot send");
-            socket.close();
         }
     }
 }
@@ -111,7 +280,8 @@ const fn default_bcast_message() -> Message {
     Message {
         protocol_version: ProtocolVersion::v1(),
         firmware_version: config::FIRMWARE_VERSION,
-        device_id: config::DEVICE_ID,
+        // Set from the settings on the first run
+        device_id: 0,
         device_serial_number: DeviceSerialNumber::zero(),
         sequence_number: 0,
         uptime_seconds: 0,
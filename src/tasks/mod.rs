@@ -1,12 +1,22 @@
 pub mod bme680;
+pub mod command;
 pub mod data_manager;
 pub mod net;
+pub mod serial;
+pub mod shell;
+pub mod sntp;
+pub mod syslog;
 pub mod watchdog;
 
 pub(crate) use self::bme680::bme680_task;
+pub(crate) use self::command::command_task;
 pub(crate) use self::data_manager::data_manager_task;
 pub(crate) use self::net::{
     eth_gpio_interrupt_handler_task, ipstack_clock_timer_task, ipstack_poll_task,
     ipstack_poll_timer_task,
 };
-pub(crate) use self::watchdog::watchdog_task;
+pub(crate) use self::serial::{log_dma_task, serial_task};
+pub(crate) use self::shell::shell_task;
+pub(crate) use self::sntp::sntp_task;
+pub(crate) use self::syslog::syslog_task;
+pub(crate) use self::watchdog::{heartbeat, unsupervised, watchdog_task, SupervisedTask};
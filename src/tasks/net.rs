@@ -1,9 +1,16 @@
-use crate::app::{
-    eth_gpio_interrupt_handler_task, ipstack_clock_timer_task, ipstack_poll_task,
-    ipstack_poll_timer_task,
+use crate::{
+    app::{
+        eth_gpio_interrupt_handler_task, ipstack_clock_timer_task, ipstack_poll_task,
+        ipstack_poll_timer_task,
+    },
+    net::stats::{self, STATISTICS},
+    tasks::{heartbeat, SupervisedTask},
 };
 use core::sync::atomic::{AtomicU32, Ordering::Relaxed};
-use smoltcp::time::Instant;
+use smoltcp::{
+    socket::dhcpv4::{Event as DhcpEvent, Socket as DhcpSocket},
+    time::Instant,
+};
 use stm32f4xx_hal::gpio::ExtiPin;
 
 /// 32-bit millisecond clock
@@ -41,9 +48,25 @@ pub(crate) fn ipstack_poll_task(ctx: ipstack_poll_task::Context) {
     let eth = ctx.shared.eth;
     let net = ctx.shared.net;
     let sockets = ctx.shared.sockets;
+    let ip_config = ctx.shared.ip_config;
     let time = NET_CLOCK.get();
+
+    heartbeat(SupervisedTask::IpstackPoll);
+
+    eth.check_health(time);
+
+    stats::add(&STATISTICS.polls, 1);
     if net.poll(time, eth, sockets) {
-        // _something_happened
+        stats::add(&STATISTICS.active_polls, 1);
+    }
+
+    if let Some(dhcp_socket_handle) = ctx.shared.dhcp_socket {
+        match sockets.get_mut::<DhcpSocket>(*dhcp_socket_handle).poll() {
+            None => (),
+            Some(DhcpEvent::Configured(lease)) => ip_config.apply_lease(net, &lease),
+            Some(DhcpEvent::Deconfigured) => ip_config.lease_lost(net),
+        }
+        ip_config.poll_fallback(net, time);
     }
 }
 
@@ -55,8 +78,7 @@ pub(crate) fn ipstack_poll_timer_task(ctx: ipstack_poll_timer_task::Context) {
 
 pub(crate) fn eth_gpio_interrupt_handler_task(ctx: eth_gpio_interrupt_handler_task::Context) {
     let eth = ctx.shared.eth;
-    if eth.driver().interrupt_pending() {
-        eth.driver().int_pin().clear_interrupt_pending_bit();
+    if eth.handle_interrupt() {
         ipstack_poll_task::spawn().ok();
     }
 }
@@ -0,0 +1,22 @@
+use crate::{
+    app::{log_dma_task, serial_task},
+    logger,
+};
+use stm32f4xx_hal::prelude::*;
+
+/// USART6, receives the shell input.
+/// Higher priority than the other tasks so received bytes aren't lost while they run.
+pub(crate) fn serial_task(ctx: serial_task::Context) {
+    let rx = ctx.local.shell_rx;
+    let state = ctx.local.state;
+
+    while let Ok(byte) = rx.read() {
+        state.receive(byte);
+    }
+}
+
+/// DMA2 stream 6, sends the buffered log output on USART6.
+/// Pended by the logger when there's new output, and by the DMA when a transfer completes.
+pub(crate) fn log_dma_task(_ctx: log_dma_task::Context) {
+    logger::drain();
+}
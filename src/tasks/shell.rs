@@ -0,0 +1,128 @@
+use crate::{
+    app::shell_task,
+    command::{self, Context},
+    logger,
+    tasks::command::MAX_RESPONSE_LEN,
+    util::TruncatingWriter,
+};
+use core::fmt::{self, Write};
+
+const BACKSPACE: u8 = 0x08;
+const DELETE: u8 = 0x7F;
+
+/// A complete command line received on the serial port
+#[derive(Copy, Clone, Debug)]
+pub struct Line {
+    buf: [u8; command::MAX_LINE_LEN],
+    len: usize,
+}
+
+impl Line {
+    pub const fn new() -> Self {
+        Self {
+            buf: [0; command::MAX_LINE_LEN],
+            len: 0,
+        }
+    }
+
+    /// Returns false if the line is full
+    fn push(&mut self, byte: u8) -> bool {
+        if self.len == self.buf.len() {
+            return false;
+        }
+        self.buf[self.len] = byte;
+        self.len += 1;
+        true
+    }
+
+    fn as_bytes(&self) -> &[u8] {
+        &self.buf[..self.len]
+    }
+}
+
+pub struct RxState {
+    line: Line,
+    /// Set when a line was too long, the rest of it is discarded
+    overflowed: bool,
+}
+
+impl RxState {
+    pub const fn new() -> Self {
+        Self {
+            line: Line::new(),
+            overflowed: false,
+        }
+    }
+
+    /// Collect a received byte, spawns the shell task on a line ending
+    pub fn receive(&mut self, byte: u8) {
+        match byte {
+            b'\r' | b'\n' => {
+                if self.overflowed {
+                    logger::write_str("error: line too long\r\n");
+                } else if self.line.len != 0 && shell_task::spawn(self.line).is_err() {
+                    logger::write_str("error: busy\r\n");
+                }
+                self.line.len = 0;
+                self.overflowed = false;
+            }
+            BACKSPACE | DELETE => self.line.len = self.line.len.saturating_sub(1),
+            _ => {
+                if !self.line.push(byte) {
+                    self.overflowed = true;
+                }
+            }
+        }
+    }
+}
+
+pub(crate) fn shell_task(ctx: shell_task::Context, line: Line) {
+    let eth = ctx.shared.eth;
+    let settings = ctx.shared.settings;
+    let flash_store = ctx.shared.flash_store;
+    let time = ctx.shared.time;
+    let ip_config = ctx.shared.ip_config;
+    let last_measurement = ctx.shared.last_measurement;
+
+    let mut response = [0_u8; MAX_RESPONSE_LEN];
+    let mut w = TruncatingWriter::new(&mut response);
+    match core::str::from_utf8(line.as_bytes()) {
+        Ok(line) => {
+            // No local echo, the prompt and command are written along with the response
+            writeln!(CrLf(&mut w), "> {}", line.trim()).ok();
+            let mut cmd_ctx = Context {
+                settings,
+                flash_store,
+                time,
+                ip_config,
+                link: eth.link(),
+                eth_errors: eth.error_counters(),
+                last_measurement: *last_measurement,
+                allow_write: true,
+            };
+            command::execute(line.trim(), &mut cmd_ctx, &mut CrLf(&mut w)).ok();
+        }
+        Err(_) => {
+            writeln!(CrLf(&mut w), "error: not UTF-8").ok();
+        }
+    }
+
+    let len = w.len();
+    // Written in one go, log records can't end up in the middle of it
+    logger::write_str(core::str::from_utf8(&response[..len]).unwrap_or("error: truncated\r\n"));
+}
+
+/// Terminals want CRLF line endings
+struct CrLf<'a, W: Write>(&'a mut W);
+
+impl<'a, W: Write> Write for CrLf<'a, W> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for (i, part) in s.split('\n').enumerate() {
+            if i != 0 {
+                self.0.write_str("\r\n")?;
+            }
+            self.0.write_str(part)?;
+        }
+        Ok(())
+    }
+}
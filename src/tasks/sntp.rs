@@ -0,0 +1,119 @@
+use crate::{
+    app::{monotonics, sntp_task},
+    config,
+    logging::{debug, info, warn, Debug2Format},
+    net::sntp,
+};
+use smoltcp::socket::udp::Socket as UdpSocket;
+use stm32f4xx_hal::prelude::*;
+
+const LOCAL_EPHEMERAL_PORT: u16 = 16001;
+
+/// How often the socket is checked for a response
+const RESPONSE_POLL_INTERVAL_MS: u32 = 50;
+
+#[derive(Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Debug)]
+pub enum SpawnArg {
+    /// Time to send a request to the server
+    SendRequest,
+    /// Check for the server's response
+    PollResponse,
+}
+
+pub struct TaskState {
+    /// Matches the response to the outstanding request
+    token: u64,
+    /// Monotonic ticks the outstanding request was sent at
+    request_sent_at: u32,
+    polls: u32,
+}
+
+impl TaskState {
+    pub const fn new() -> Self {
+        Self {
+            token: 0,
+            request_sent_at: 0,
+            polls: 0,
+        }
+    }
+}
+
+pub(crate) fn sntp_task(ctx: sntp_task::Context, arg: SpawnArg) {
+    let state = ctx.local.state;
+    let sockets = ctx.shared.sockets;
+    let sntp_socket_handle = ctx.shared.sntp_socket;
+    let time = ctx.shared.time;
+    let settings = ctx.shared.settings;
+
+    let socket = sockets.get_mut::<UdpSocket>(*sntp_socket_handle);
+    let now = monotonics::now().ticks();
+
+    match arg {
+        SpawnArg::SendRequest => {
+            if !socket.is_open() {
+                socket.bind(LOCAL_EPHEMERAL_PORT).unwrap();
+            }
+
+            // Discard any late responses to previous requests
+            while socket.recv().is_ok() {}
+
+            state.token = (u64::from(now) << 32) | u64::from((state.token as u32).wrapping_add(1));
+            match socket.send(
+                sntp::PACKET_LEN,
+                (settings.sntp_server_address, sntp::PORT).into(),
+            ) {
+                Ok(buf) => {
+                    sntp::emit_request(state.token, buf);
+                    state.request_sent_at = now;
+                    state.polls = 0;
+                    sntp_task::spawn_after(
+                        RESPONSE_POLL_INTERVAL_MS.millis(),
+                        SpawnArg::PollResponse,
+                    )
+                    .unwrap();
+                }
+                Err(e) => {
+                    warn!("SNTP: failed to send request. {:?}", Debug2Format(&e));
+                    schedule_request(config::SNTP_RETRY_INTERVAL_SEC);
+                }
+            }
+        }
+        SpawnArg::PollResponse => match socket.recv() {
+            Ok((buf, _)) => match sntp::parse_response(state.token, buf) {
+                Ok(server_time_us) => {
+                    // Assume a symmetric path delay
+                    let rtt_us = now.wrapping_sub(state.request_sent_at);
+                    let unix_us = server_time_us + u64::from(rtt_us / 2);
+                    if time.unix_time().is_none() {
+                        info!("SNTP: initial time sync");
+                    }
+                    // The RTC calendar has a 1 second resolution
+                    time.set_unix_time((unix_us + 500_000) / 1_000_000);
+                    debug!("SNTP: synced, rtt {} us", rtt_us);
+                    schedule_request(settings.sntp_sync_interval_sec);
+                }
+                Err(e) => {
+                    warn!("SNTP: invalid response. {:?}", Debug2Format(&e));
+                    schedule_request(config::SNTP_RETRY_INTERVAL_SEC);
+                }
+            },
+            Err(_) => {
+                state.polls += 1;
+                if state.polls * RESPONSE_POLL_INTERVAL_MS >= config::SNTP_RESPONSE_TIMEOUT_MS {
+                    warn!("SNTP: request timed out");
+                    schedule_request(config::SNTP_RETRY_INTERVAL_SEC);
+                } else {
+                    sntp_task::spawn_after(
+                        RESPONSE_POLL_INTERVAL_MS.millis(),
+                        SpawnArg::PollResponse,
+                    )
+                    .unwrap();
+                }
+            }
+        },
+    }
+}
+
+fn schedule_request(delay_sec: u32) {
+    sntp_task::spawn_after(delay_sec.secs(), SpawnArg::SendRequest).unwrap();
+}
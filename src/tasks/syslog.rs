@@ -0,0 +1,72 @@
+use crate::{
+    app::syslog_task,
+    config,
+    logging::{debug, Debug2Format},
+    net::syslog::{self, MAX_ENTRY_LEN, MAX_MESSAGE_LEN},
+    time_service,
+};
+use smoltcp::socket::udp::Socket as UdpSocket;
+use stm32f4xx_hal::prelude::*;
+
+const LOCAL_EPHEMERAL_PORT: u16 = 16002;
+
+pub struct TaskState {
+    /// Uptime the current one-second rate limit window started at
+    window_start_ms: u64,
+    sent_in_window: u32,
+}
+
+impl TaskState {
+    pub const fn new() -> Self {
+        Self {
+            window_start_ms: 0,
+            sent_in_window: 0,
+        }
+    }
+}
+
+pub(crate) fn syslog_task(ctx: syslog_task::Context) {
+    let state = ctx.local.state;
+    let sockets = ctx.shared.sockets;
+    let syslog_socket_handle = ctx.shared.syslog_socket;
+    let settings = ctx.shared.settings;
+    let ip_config = ctx.shared.ip_config;
+
+    let socket = sockets.get_mut::<UdpSocket>(*syslog_socket_handle);
+    let hostname = ip_config.address().map(|cidr| cidr.address());
+
+    if !settings.syslog_collector() {
+        // Records queued before the collector was removed
+        syslog::clear();
+    } else if let Some(hostname) = hostname {
+        if !socket.is_open() {
+            socket.bind(LOCAL_EPHEMERAL_PORT).unwrap();
+        }
+
+        let now_ms = time_service::uptime_us() / 1_000;
+        if now_ms.saturating_sub(state.window_start_ms) >= 1_000 {
+            state.window_start_ms = now_ms;
+            state.sent_in_window = 0;
+        }
+
+        // Records beyond the rate limit wait in the queue
+        let mut entry_buf = [0_u8; MAX_ENTRY_LEN];
+        let mut msg = [0_u8; MAX_MESSAGE_LEN];
+        while state.sent_in_window < config::SYSLOG_MAX_MESSAGES_PER_SEC && socket.can_send() {
+            let Some(entry) = syslog::pop(&mut entry_buf) else {
+                break;
+            };
+            let len = syslog::emit(&entry, hostname, &mut msg);
+            state.sent_in_window += 1;
+            // Not logged above debug, a failure would queue more records
+            if let Err(e) = socket.send_slice(
+                &msg[..len],
+                (settings.syslog_address, settings.syslog_port).into(),
+            ) {
+                debug!("SYSLOG: failed to send. {:?}", Debug2Format(&e));
+            }
+        }
+    }
+
+    syslog_task::spawn_after(config::SYSLOG_POLL_INTERVAL_MS.millis()).unwrap();
+}
@@ -1,12 +1,98 @@
-use crate::{app::watchdog_task, config};
+use crate::{
+    app::{monotonics, watchdog_task},
+    config,
+    logging::{error, Debug2Format},
+};
+use core::sync::atomic::{
+    AtomicBool, AtomicU32,
+    Ordering::{Acquire, Relaxed, Release},
+};
 use stm32f4xx_hal::prelude::*;
 
+/// Tasks that must check in regularly for the watchdog to be fed
+#[derive(Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Debug)]
+pub enum SupervisedTask {
+    Bme680,
+    DataManager,
+    IpstackPoll,
+}
+
+impl SupervisedTask {
+    const ALL: [SupervisedTask; 3] = [
+        SupervisedTask::Bme680,
+        SupervisedTask::DataManager,
+        SupervisedTask::IpstackPoll,
+    ];
+
+    fn deadline_ms(self) -> u32 {
+        match self {
+            SupervisedTask::Bme680 => config::BME680_TASK_DEADLINE_MS,
+            SupervisedTask::DataManager => config::DATA_MANAGER_TASK_DEADLINE_MS,
+            SupervisedTask::IpstackPoll => config::IPSTACK_POLL_TASK_DEADLINE_MS,
+        }
+    }
+}
+
+/// Monotonic ticks (microseconds) of each task's last check-in, indexed by `SupervisedTask`.
+/// The monotonic starts at zero, which counts as a check-in at boot.
+static HEARTBEATS: [AtomicU32; SupervisedTask::ALL.len()] =
+    [AtomicU32::new(0), AtomicU32::new(0), AtomicU32::new(0)];
+
+/// Set while `unsupervised` runs
+static SUSPENDED: AtomicBool = AtomicBool::new(false);
+
+/// Check in with the supervisor, called each time the task runs
+pub(crate) fn heartbeat(task: SupervisedTask) {
+    HEARTBEATS[task as usize].store(monotonics::now().ticks(), Relaxed);
+}
+
+/// Run `f`, which stalls the other tasks (a flash sector erase takes up to a few
+/// seconds), without counting that time against their deadlines. The IWDG still
+/// runs, `f` must complete within `WATCHDOG_RESET_PERIOD_MS`.
+pub(crate) fn unsupervised<R, F: FnOnce() -> R>(f: F) -> R {
+    SUSPENDED.store(true, Release);
+    let ret = f();
+    let now = monotonics::now().ticks();
+    HEARTBEATS.iter().for_each(|h| h.store(now, Relaxed));
+    SUSPENDED.store(false, Release);
+    ret
+}
+
+pub struct TaskState {
+    starved: bool,
+}
+
+impl TaskState {
+    pub const fn new() -> Self {
+        Self { starved: false }
+    }
+}
+
 pub(crate) fn watchdog_task(ctx: watchdog_task::Context) {
     let watchdog = ctx.local.watchdog;
     let led = ctx.local.led;
+    let state = ctx.local.state;
+
+    if !state.starved && !SUSPENDED.load(Acquire) {
+        let now = monotonics::now().ticks();
+        for task in SupervisedTask::ALL.iter().copied() {
+            // Wrapping is fine, deadlines are much shorter than the ~71 minute timer period
+            let elapsed_ms = now.wrapping_sub(HEARTBEATS[task as usize].load(Relaxed)) / 1_000;
+            if elapsed_ms > task.deadline_ms() {
+                error!(
+                    "Watchdog: {:?} task starved, last check-in {} ms ago, letting the watchdog reset",
+                    Debug2Format(&task),
+                    elapsed_ms
+                );
+                state.starved = true;
+            }
+        }
+    }
 
-    watchdog.feed();
-    led.toggle();
+    if !state.starved {
+        watchdog.feed();
+        led.toggle();
+    }
 
     watchdog_task::spawn_after(config::WATCHDOG_TASK_INTERVAL_MS.millis()).unwrap();
 }
@@ -0,0 +1,122 @@
+//! UTC time and monotonic uptime.
+//!
+//! UTC time comes from the RTC calendar, which survives watchdog resets,
+//! and is kept in sync by SNTP. Uptime is extended from the 32-bit
+//! microsecond monotonic. Both are also available to the logger, which runs
+//! in any context, through `uptime_us` and `utc_now`.
+//!
+//! UTC time is read as the uptime plus an offset taken from the RTC, so the
+//! log timestamps and the broadcast datetime agree. The offset follows the
+//! RTC, see `TimeService::sync_from_rtc`.
+
+use crate::rtc::{ClockSource, Rtc};
+use core::{
+    cell::Cell,
+    sync::atomic::{AtomicU32, Ordering::Relaxed},
+};
+use cortex_m::interrupt::{self, Mutex};
+use stm32f4xx_hal::pac;
+
+pub use bme680_env_monitor::calendar::{days_from_civil, UtcDateTime};
+
+#[derive(Copy, Clone)]
+struct Uptime {
+    /// Microseconds since boot at `mono_ref`
+    us: u64,
+    /// Monotonic ticks the uptime was last advanced at
+    mono_ref: u32,
+}
+
+static UPTIME: Mutex<Cell<Uptime>> = Mutex::new(Cell::new(Uptime { us: 0, mono_ref: 0 }));
+
+/// Unix time minus the uptime, in seconds, 0 until the time is set
+static UNIX_OFFSET_SEC: AtomicU32 = AtomicU32::new(0);
+
+/// Microseconds since boot, from any context.
+///
+/// This must be called more often than the monotonic wraps (~71 minutes)
+/// to keep track of time, the data manager does.
+pub fn uptime_us() -> u64 {
+    interrupt::free(|cs| {
+        // The monotonic's counter, read directly so this also works in init,
+        // before the monotonic is started and the counter reads 0
+        let now = unsafe { (*pac::TIM2::ptr()).cnt.read().bits() };
+        let cell = UPTIME.borrow(cs);
+        let mut uptime = cell.get();
+        uptime.us += u64::from(now.wrapping_sub(uptime.mono_ref));
+        uptime.mono_ref = now;
+        cell.set(uptime);
+        uptime.us
+    })
+}
+
+/// Seconds since the unix epoch from the uptime and the last time set, from any context
+pub fn unix_time_now() -> Option<u64> {
+    match UNIX_OFFSET_SEC.load(Relaxed) {
+        0 => None,
+        offset => Some(u64::from(offset) + uptime_us() / 1_000_000),
+    }
+}
+
+/// UTC time from the uptime and the last time set, to the second, from any context
+pub fn utc_now() -> Option<UtcDateTime> {
+    unix_time_now().map(UtcDateTime::from_unix)
+}
+
+fn set_unix_offset(unix_secs: u64) {
+    let offset = unix_secs.saturating_sub(uptime_us() / 1_000_000);
+    UNIX_OFFSET_SEC.store(offset.min(u64::from(u32::MAX)) as u32, Relaxed);
+}
+
+pub struct TimeService {
+    rtc: Rtc,
+}
+
+impl TimeService {
+    pub fn new(rtc: Rtc) -> Self {
+        if let Some(unix_secs) = rtc.unix_time() {
+            set_unix_offset(unix_secs);
+        }
+        Self { rtc }
+    }
+
+    /// Microseconds since boot, see `uptime_us`
+    pub fn uptime_us(&mut self) -> u64 {
+        uptime_us()
+    }
+
+    pub fn uptime_seconds(&mut self) -> u32 {
+        (self.uptime_us() / 1_000_000) as u32
+    }
+
+    pub fn clock_source(&self) -> ClockSource {
+        self.rtc.clock_source()
+    }
+
+    /// Seconds since the unix epoch, `None` if the time was never set
+    pub fn unix_time(&self) -> Option<u64> {
+        unix_time_now()
+    }
+
+    /// Re-sync the UTC offset from the RTC, called periodically.
+    /// The uptime and the RTC run from different oscillators and drift apart.
+    pub fn sync_from_rtc(&mut self) {
+        let Some(rtc_secs) = self.rtc.unix_time() else {
+            return;
+        };
+        // Both count whole seconds, at different phases, 1 s apart isn't drift
+        let drift = unix_time_now().map(|t| t.abs_diff(rtc_secs));
+        if drift.map(|d| d > 1).unwrap_or(true) {
+            set_unix_offset(rtc_secs);
+        }
+    }
+
+    pub fn utc(&self) -> Option<UtcDateTime> {
+        self.unix_time().map(UtcDateTime::from_unix)
+    }
+
+    pub fn set_unix_time(&mut self, unix_secs: u64) {
+        self.rtc.set_unix_time(unix_secs);
+        set_unix_offset(unix_secs);
+    }
+}
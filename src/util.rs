@@ -1,8 +1,44 @@
+use core::fmt;
 use wire_protocols::DeviceSerialNumber;
 
-pub(crate) fn read_device_serial_number() -> DeviceSerialNumber {
+/// The 96-bit unique device ID
+pub(crate) fn read_device_uid() -> [u32; 3] {
     let word0 = unsafe { *(0x1FFF_7A10 as *const u32) };
     let word1 = unsafe { *(0x1FFF_7A14 as *const u32) };
     let word2 = unsafe { *(0x1FFF_7A18 as *const u32) };
+    [word0, word1, word2]
+}
+
+pub(crate) fn read_device_serial_number() -> DeviceSerialNumber {
+    let [word0, word1, word2] = read_device_uid();
     DeviceSerialNumber::new(word0, word1, word2)
 }
+
+/// A `fmt::Write` into a fixed buffer, silently dropping what doesn't fit.
+/// Only whole characters are written, the output stays valid UTF-8.
+pub(crate) struct TruncatingWriter<'a> {
+    buf: &'a mut [u8],
+    len: usize,
+}
+
+impl<'a> TruncatingWriter<'a> {
+    pub(crate) fn new(buf: &'a mut [u8]) -> Self {
+        Self { buf, len: 0 }
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.len
+    }
+}
+
+impl<'a> fmt::Write for TruncatingWriter<'a> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let mut n = s.len().min(self.buf.len() - self.len);
+        while !s.is_char_boundary(n) {
+            n -= 1;
+        }
+        self.buf[self.len..self.len + n].copy_from_slice(&s.as_bytes()[..n]);
+        self.len += n;
+        Ok(())
+    }
+}